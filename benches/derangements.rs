@@ -0,0 +1,28 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use derangements::{derangements, derangements_by_range, fast_permutations};
+
+/// Compare `derangements`, which builds each derangement by backtracking, with filtering all
+/// permutations (as `derangements` did before) and with the range-specific generator.
+fn bench_derangements(c: &mut Criterion) {
+    let mut group = c.benchmark_group("derangements");
+    group.sample_size(10);
+    for n in [8, 10] {
+        group.bench_with_input(BenchmarkId::new("backtracking", n), &n, |b, &n| {
+            b.iter(|| derangements(0..n, n).count())
+        });
+        group.bench_with_input(BenchmarkId::new("filtered", n), &n, |b, &n| {
+            b.iter(|| {
+                fast_permutations(0..n, n)
+                    .filter(|x| x.iter().enumerate().all(|(i, &v)| i != v))
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("by_range", n), &n, |b, &n| {
+            b.iter(|| derangements_by_range(n).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_derangements);
+criterion_main!(benches);
@@ -0,0 +1,217 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+const WORD: usize = u64::BITS as usize;
+
+/// Iterative depth-first search over k-length injective selections of the indices `0..n`.
+///
+/// Each call to `advance` moves to the next selection (in lexicographic order of the indices)
+/// for which every placement was accepted by the `allowed` callback. The callback receives the
+/// prefix that is already placed (so the position of the candidate is `prefix.len()`), the same
+/// indices as a bitset, and the candidate index, which allows rejecting a partial selection as
+/// soon as it becomes invalid.
+/// A search can also be restricted to the selections that start with a given prefix.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "BacktrackingState"))]
+pub(crate) struct Backtracking {
+    stack: Vec<usize>,
+    used: Vec<u64>,
+    n: usize,
+    start: bool,
+    floor: usize,
+    k: usize,
+}
+
+/// The fields of a deserialized `Backtracking`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct BacktrackingState {
+    stack: Vec<usize>,
+    used: Vec<u64>,
+    n: usize,
+    start: bool,
+    floor: usize,
+    k: usize,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<BacktrackingState> for Backtracking {
+    type Error = &'static str;
+
+    fn try_from(state: BacktrackingState) -> Result<Self, Self::Error> {
+        if state.used.len() != state.n.div_ceil(WORD) {
+            return Err("used indices of the wrong length");
+        }
+        if state.stack.len() > state.k || state.floor > state.stack.len() {
+            return Err("selection longer than k");
+        }
+        let mut search = Backtracking::new(state.n, state.k);
+        for &index in &state.stack {
+            if index >= state.n || search.is_used(index) {
+                return Err("selection is not a set of distinct indices below n");
+            }
+            search.push(index);
+        }
+        if search.used != state.used {
+            return Err("used indices don't match the selection");
+        }
+        Ok(Backtracking {
+            start: state.start,
+            floor: state.floor,
+            ..search
+        })
+    }
+}
+
+impl Backtracking {
+    pub(crate) fn new(n: usize, k: usize) -> Self {
+        Backtracking {
+            stack: Vec::with_capacity(k),
+            used: vec![0; n.div_ceil(u64::BITS as usize)],
+            n,
+            start: true,
+            floor: 0,
+            k,
+        }
+    }
+
+    /// A search over the selections that start with `prefix`, which should itself be accepted.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn with_prefix(n: usize, k: usize, prefix: &[usize]) -> Self {
+        let mut search = Backtracking::new(n, k);
+        for &index in prefix {
+            search.push(index);
+        }
+        search.floor = prefix.len();
+        search
+    }
+
+    /// The number of indices n and the length k of the selections.
+    #[cfg(feature = "serde")]
+    pub(crate) fn dimensions(&self) -> (usize, usize) {
+        (self.n, self.k)
+    }
+
+    /// The indices of the current selection, one per position.
+    pub(crate) fn indices(&self) -> &[usize] {
+        &self.stack
+    }
+
+    /// Move to the next accepted selection; returns `false` once the search space is exhausted.
+    pub(crate) fn advance<F>(&mut self, mut allowed: F) -> bool
+    where
+        F: FnMut(&[usize], &[u64], usize) -> bool,
+    {
+        let n = self.n;
+        let mut from = if self.start {
+            self.start = false;
+            if self.k > n {
+                return false;
+            }
+            if self.stack.len() == self.k {
+                return true;
+            }
+            0
+        } else {
+            match self.pop() {
+                None => return false,
+                Some(index) => index + 1,
+            }
+        };
+
+        loop {
+            let candidate = (from..n)
+                .find(|&index| self.available(index) && allowed(&self.stack, &self.used, index));
+            match candidate {
+                Some(index) => {
+                    self.push(index);
+                    if self.stack.len() == self.k {
+                        return true;
+                    }
+                    from = 0;
+                }
+                None => match self.pop() {
+                    None => return false,
+                    Some(index) => from = index + 1,
+                },
+            }
+        }
+    }
+
+    /// Skip the next `skip` accepted selections without visiting them one by one, where `count`
+    /// gives the number of accepted selections that start with a given prefix. `skip` has to be
+    /// less than the number of selections that are left.
+    pub(crate) fn skip<F, C>(&mut self, mut skip: u128, mut allowed: F, mut count: C)
+    where
+        F: FnMut(&[usize], &[u64], usize) -> bool,
+        C: FnMut(&[usize]) -> u128,
+    {
+        if skip == 0 {
+            return;
+        }
+        let n = self.n;
+        let mut from = if self.start && self.stack.len() < self.k {
+            0
+        } else {
+            // The current selection is either the last one returned, or the next one to return
+            if self.start {
+                skip -= 1;
+            }
+            match self.pop() {
+                None => return,
+                Some(index) => index + 1,
+            }
+        };
+        self.start = false;
+
+        // Skip whole subtrees, and descend into the one that contains the target
+        loop {
+            let candidate = (from..n)
+                .find(|&index| self.available(index) && allowed(&self.stack, &self.used, index));
+            match candidate {
+                Some(index) => {
+                    self.push(index);
+                    let subtree = count(&self.stack);
+                    if skip >= subtree {
+                        skip -= subtree;
+                        self.pop();
+                        from = index + 1;
+                    } else if self.stack.len() == self.k {
+                        // Let the next call to advance return this selection
+                        self.start = true;
+                        return;
+                    } else {
+                        from = 0;
+                    }
+                }
+                None => match self.pop() {
+                    None => return,
+                    Some(index) => from = index + 1,
+                },
+            }
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / WORD] & (1 << (index % WORD)) != 0
+    }
+
+    fn available(&self, index: usize) -> bool {
+        !self.is_used(index)
+    }
+
+    fn push(&mut self, index: usize) {
+        self.stack.push(index);
+        self.used[index / WORD] |= 1 << (index % WORD);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.stack.len() == self.floor {
+            return None;
+        }
+        let index = self.stack.pop()?;
+        self.used[index / WORD] &= !(1 << (index % WORD));
+        Some(index)
+    }
+}
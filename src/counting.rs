@@ -0,0 +1,1058 @@
+use crate::cycles::CycleSpec;
+use crate::permanent::{
+    band_rook_numbers, diagonal_band_rook_numbers, permanent, rook_numbers, BAND_LIMIT,
+};
+use crate::restriction::Restriction;
+use itertools::Itertools;
+#[cfg(feature = "bigint")]
+use num_bigint::BigUint;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Range;
+
+/// Unsigned integer arithmetic used for exact counts: overflow-checked for `u128`, and never
+/// failing for the arbitrary-precision backend.
+pub(crate) trait Count: Sized + Clone {
+    fn from_usize(x: usize) -> Self;
+    fn add_checked(&self, other: &Self) -> Option<Self>;
+    fn sub_checked(&self, other: &Self) -> Option<Self>;
+    fn mul_checked(&self, other: &Self) -> Option<Self>;
+    fn div_exact(&self, other: usize) -> Self;
+    fn rem_usize(&self, other: usize) -> usize;
+}
+
+impl Count for u128 {
+    fn from_usize(x: usize) -> Self {
+        x as u128
+    }
+
+    fn add_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_add(*other)
+    }
+
+    fn sub_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_sub(*other)
+    }
+
+    fn mul_checked(&self, other: &Self) -> Option<Self> {
+        self.checked_mul(*other)
+    }
+
+    fn div_exact(&self, other: usize) -> Self {
+        self / other as u128
+    }
+
+    fn rem_usize(&self, other: usize) -> usize {
+        (self % other as u128) as usize
+    }
+}
+
+#[cfg(feature = "bigint")]
+impl Count for BigUint {
+    fn from_usize(x: usize) -> Self {
+        BigUint::from(x)
+    }
+
+    fn add_checked(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn sub_checked(&self, other: &Self) -> Option<Self> {
+        (self >= other).then(|| self - other)
+    }
+
+    fn mul_checked(&self, other: &Self) -> Option<Self> {
+        Some(self * other)
+    }
+
+    fn div_exact(&self, other: usize) -> Self {
+        self / other
+    }
+
+    fn rem_usize(&self, other: usize) -> usize {
+        (self % other).try_into().unwrap()
+    }
+}
+
+/// Number of permutations of k out of n elements: n! / (n - k)!
+pub(crate) fn permutations<C: Count>(n: usize, k: usize) -> Option<C> {
+    if k > n {
+        return Some(C::from_usize(0));
+    }
+    (n - k + 1..=n).try_fold(C::from_usize(1), |acc, x| {
+        acc.mul_checked(&C::from_usize(x))
+    })
+}
+
+/// Number of distinct orderings of a multiset with the given multiplicities
+pub(crate) fn multinomial<C: Count>(multiplicities: &[usize]) -> Option<C> {
+    let mut total = 0;
+    multiplicities.iter().try_fold(C::from_usize(1), |acc, &m| {
+        total += m;
+        acc.mul_checked(&binomial(total, m)?)
+    })
+}
+
+/// Elementary symmetric polynomials e_0 up to e_len of the given values
+pub(crate) fn elementary_symmetric<C: Count>(values: &[usize]) -> Option<Vec<C>> {
+    let mut e = vec![C::from_usize(1)];
+    for &x in values {
+        let x = C::from_usize(x);
+        e.push(C::from_usize(0));
+        for t in (1..e.len()).rev() {
+            e[t] = e[t].add_checked(&e[t - 1].mul_checked(&x)?)?;
+        }
+    }
+    Some(e)
+}
+
+/// Evaluate sum_t (-1)^t terms_t, as a running sum with a separate sign.
+///
+/// For inclusion-exclusion the terms usually decrease, so that the running sum stays between 0
+/// and the first term, while the sums of the even and odd terms alone could overflow long before
+/// the result does. Returns `None` if the result would be negative.
+pub(crate) fn alternating_sum<C, T>(terms: T) -> Option<C>
+where
+    C: Count,
+    T: IntoIterator<Item = Option<C>>,
+{
+    let (mut total, mut negative) = (C::from_usize(0), false);
+    for (t, term) in terms.into_iter().enumerate() {
+        let term = term?;
+        if (t % 2 == 1) == negative {
+            total = total.add_checked(&term)?;
+        } else if let Some(difference) = total.sub_checked(&term) {
+            total = difference;
+        } else {
+            total = term.sub_checked(&total)?;
+            negative = !negative;
+        }
+    }
+    match negative {
+        // Only a negative zero is not negative
+        true => C::from_usize(0).sub_checked(&total),
+        false => Some(total),
+    }
+}
+
+/// Number of k-permutations of n values where position i may not hold any of its own
+/// `forbidden[i]` values (no value being forbidden at more than one position).
+///
+/// By inclusion-exclusion over the positions that do hold one of their own values, this is
+/// sum_t (-1)^t e_t(forbidden) (n - t)! / (n - k)!.
+pub(crate) fn forbidden_permutations<C: Count>(
+    n: usize,
+    k: usize,
+    forbidden: &[usize],
+) -> Option<C> {
+    if k > n {
+        return Some(C::from_usize(0));
+    }
+    // Every position forbidding a value of its own is a plain derangement
+    if k == n && forbidden.iter().all(|&x| x == 1) {
+        return subfactorial(n);
+    }
+    let e = elementary_symmetric::<C>(forbidden)?;
+    alternating_sum(
+        e.iter()
+            .enumerate()
+            .take_while(|(t, _)| *t <= k)
+            .map(|(t, e_t)| e_t.mul_checked(&permutations(n - t, k - t)?)),
+    )
+}
+
+/// Number of permutations of n values where exactly m positions hold one of their own values,
+/// with position i having `own[i]` values of its own (no value being own to more than one
+/// position).
+///
+/// By inclusion-exclusion over the sets of j >= m positions that do hold one of their own values,
+/// this is sum_j (-1)^(j - m) C(j, m) e_j(own) (n - j)!.
+pub(crate) fn own_rencontres<C: Count>(n: usize, m: usize, own: &[usize]) -> Option<C> {
+    let e = elementary_symmetric::<C>(own)?;
+    alternating_sum(e.iter().enumerate().skip(m).map(|(j, e_j)| {
+        binomial::<C>(j, m)?
+            .mul_checked(e_j)?
+            .mul_checked(&permutations(n - j, n - j)?)
+    }))
+}
+
+/// Size hint for an iterator with an exactly known (or overflowed) number of remaining items
+pub(crate) fn size_hint(remaining: Option<u128>) -> (usize, Option<usize>) {
+    match remaining.and_then(|x| usize::try_from(x).ok()) {
+        Some(x) => (x, Some(x)),
+        None => (usize::MAX, None),
+    }
+}
+
+/// Compute x * y / d, where d has to divide x * y.
+///
+/// The common factor of x and d is divided out first, so that this only overflows if the result
+/// does not fit.
+pub(crate) fn mul_div_exact<C: Count>(x: &C, y: usize, d: usize) -> Option<C> {
+    let (mut a, mut b) = (x.rem_usize(d), d);
+    while a > 0 {
+        (a, b) = (b % a, a);
+    }
+    x.div_exact(b).mul_checked(&C::from_usize(y / (d / b)))
+}
+
+/// Number of ways to choose k out of n elements
+pub(crate) fn binomial<C: Count>(n: usize, k: usize) -> Option<C> {
+    if k > n {
+        return Some(C::from_usize(0));
+    }
+    let k = k.min(n - k);
+    (0..k).try_fold(C::from_usize(1), |acc, i| mul_div_exact(&acc, n - i, i + 1))
+}
+
+/// Subfactorial !n, using !n = (n - 1)(!(n - 1) + !(n - 2))
+pub(crate) fn subfactorial<C: Count>(n: usize) -> Option<C> {
+    let (mut prev, mut curr) = (C::from_usize(1), C::from_usize(0));
+    if n == 0 {
+        return Some(prev);
+    }
+    for m in 2..=n {
+        let next = C::from_usize(m - 1).mul_checked(&prev.add_checked(&curr)?)?;
+        prev = curr;
+        curr = next;
+    }
+    Some(curr)
+}
+
+/// Number of even or odd derangements of 0..n.
+///
+/// The difference between the number of even and odd derangements is (-1)^(n - 1) (n - 1), so
+/// each is half of !n plus or minus n - 1, except for the single (even) derangement of nothing.
+pub(crate) fn parity_derangements<C: Count>(n: usize, odd: bool) -> Option<C> {
+    if n == 0 {
+        return Some(C::from_usize(usize::from(!odd)));
+    }
+    let total = subfactorial::<C>(n)?;
+    let difference = C::from_usize(n - 1);
+    if odd == (n % 2 == 1) {
+        Some(total.sub_checked(&difference)?.div_exact(2))
+    } else {
+        Some(total.add_checked(&difference)?.div_exact(2))
+    }
+}
+
+/// Number of k-permutations of 0..n without fixed points.
+///
+/// With a(n, 0) = 1, this uses a(n, k) = (n - k + 1) a(n, k - 1) - a(n - 1, k - 1): out of the
+/// arrangements where the first k - 1 positions are deranged, remove those where position k - 1
+/// is fixed.
+pub(crate) fn partial_derangements<C: Count>(n: usize, k: usize) -> Option<C> {
+    if k > n {
+        return Some(C::from_usize(0));
+    }
+
+    // Keep a(n - i, j) for the current j in `rows[i]`
+    let mut rows = vec![C::from_usize(1); k + 1];
+    for j in 0..k {
+        for i in 0..k - j {
+            rows[i] = C::from_usize(n - i - j)
+                .mul_checked(&rows[i])?
+                .sub_checked(&rows[i + 1])?;
+        }
+        rows.pop();
+    }
+    Some(rows.swap_remove(0))
+}
+
+/// Rencontres number D(n, m): choose the m fixed points and derange the rest
+pub(crate) fn rencontres<C: Count>(n: usize, m: usize) -> Option<C> {
+    if m > n {
+        return Some(C::from_usize(0));
+    }
+    binomial::<C>(n, m)?.mul_checked(&subfactorial(n - m)?)
+}
+
+/// Number of permutations of 0..n where no value v is at the positions v up to v + w - 1, which
+/// wrap around if `circular`.
+///
+/// By inclusion-exclusion over the positions that do hold a forbidden value, this is
+/// sum_t (-1)^t r_t (n - t)!, with r_t the rook numbers of the forbidden band. For w = 2 these are
+/// known in closed form: 2n / (2n - t) C(2n - t, t) with wrapping, which gives Touchard's formula
+/// for the ménage numbers, and C(2n - t, t) without. Wider bands are counted with a transfer
+/// matrix over the rows, or for circular bands of more than half of n by reducing the board.
+pub(crate) fn window_derangements<C: Count>(n: usize, w: usize, circular: bool) -> Option<C> {
+    // The first term is n!, so the others don't have to be computed if that doesn't fit
+    permutations::<C>(n, n)?;
+    let w = w.min(n);
+    let r = match w {
+        0 => return permutations(n, n),
+        1 => return subfactorial(n),
+        2 if circular => (0..=n)
+            .map(|t| mul_div_exact(&binomial::<C>(2 * n - t, t)?, 2 * n, 2 * n - t))
+            .collect::<Option<Vec<_>>>()?,
+        2 => (0..=n)
+            .map(|t| binomial::<C>(2 * n - t, t))
+            .collect::<Option<Vec<_>>>()?,
+        _ if circular && n < 2 * w => {
+            let cells = (0..n)
+                .flat_map(|value| (value..value + w).map(move |position| (position % n, value)))
+                .collect_vec();
+            rook_numbers(&cells)?
+        }
+        _ => band_rook_numbers(n, w, circular)?,
+    };
+    alternating_sum(
+        r.iter()
+            .enumerate()
+            .map(|(t, r_t)| r_t.mul_checked(&permutations(n - t, n - t)?)),
+    )
+}
+
+/// Number of derangements of 0..n where every element moves at most d positions.
+///
+/// This is a transfer matrix over the positions: position p can only hold the values p - d up to
+/// p + d, so it suffices to keep the number of partial permutations for each set of used values
+/// in that window. Values that leave the window without being used can never be placed anymore.
+/// Returns `None` if the width 2d + 1 is above `BAND_LIMIT`, unless every derangement is allowed.
+pub(crate) fn max_displacement_derangements<C: Count>(n: usize, d: usize) -> Option<C> {
+    if d.saturating_add(1) >= n {
+        return subfactorial(n);
+    }
+    let width = 2 * d + 1;
+    if width > BAND_LIMIT {
+        return None;
+    }
+
+    // Bit j of a state is whether value p - d + j is used, before position p; values below 0 are
+    // never available, and so count as used
+    let mut states: Vec<Option<C>> = vec![None; 1 << width];
+    states[(1 << d) - 1] = Some(C::from_usize(1));
+    for p in 0..n {
+        let mut next: Vec<Option<C>> = vec![None; 1 << width];
+        for (mask, count) in states.iter().enumerate() {
+            let Some(count) = count else { continue };
+            for j in (0..width).filter(|&j| j != d && mask & (1 << j) == 0 && p + j < n + d) {
+                // Move the window on by one, which requires the value that leaves it to be used
+                let placed = mask | (1 << j);
+                if placed & 1 == 0 {
+                    continue;
+                }
+                let state = &mut next[placed >> 1];
+                *state = Some(match state {
+                    Some(x) => x.add_checked(count)?,
+                    None => count.clone(),
+                });
+            }
+        }
+        states = next;
+    }
+
+    // The values n - d up to n - 1 have to be used, and the ones from n on are not available
+    states.swap_remove((1 << d) - 1).or(Some(C::from_usize(0)))
+}
+
+/// Number of derangements of 0..n where every element moves at least d positions.
+///
+/// By inclusion-exclusion over the positions that do hold a value less than d away, this is
+/// sum_t (-1)^t r_t (n - t)!, with r_t the rook numbers of the band of those pairs around the
+/// diagonal, which come from a transfer matrix as well.
+pub(crate) fn min_displacement_derangements<C: Count>(n: usize, d: usize) -> Option<C> {
+    let h = d.max(1) - 1;
+    if n > 0 && h + 1 >= n {
+        return Some(C::from_usize(0));
+    }
+    // The first term is n!, so the others don't have to be computed if that doesn't fit
+    permutations::<C>(n, n)?;
+    let r = diagonal_band_rook_numbers::<C>(n, h)?;
+    alternating_sum(
+        r.iter()
+            .enumerate()
+            .map(|(t, r_t)| r_t.mul_checked(&permutations(n - t, n - t)?)),
+    )
+}
+
+/// Number of involutions of 0..n without fixed points: the double factorial (n - 1)!! for even n,
+/// as the smallest element has n - 1 partners to choose from and the rest is paired up likewise.
+pub(crate) fn involutive_derangements<C: Count>(n: usize) -> Option<C> {
+    if n % 2 == 1 {
+        return Some(C::from_usize(0));
+    }
+    (1..n).step_by(2).try_fold(C::from_usize(1), |acc, x| {
+        acc.mul_checked(&C::from_usize(x))
+    })
+}
+
+/// Number of derangements of 0..n with the cycle lengths allowed by `spec`.
+///
+/// For an exact partition with m_l cycles of length l, the cycles of each length are formed in
+/// turn: there are C(r, l m_l) ways to pick their elements out of the r that are left, and
+/// prod_{j = 1..m_l} C(jl - 1, l - 1) (l - 1)! ways to split those into cycles, each time completing
+/// the cycle of the smallest element. Otherwise the cycle of the smallest element is chosen first,
+/// which gives a(r) = sum_l (r - 1)! / (r - l)! a(r - l) over the allowed lengths l.
+pub(crate) fn cycle_type_derangements<C: Count>(n: usize, spec: &CycleSpec) -> Option<C> {
+    if n == 0 {
+        return Some(C::from_usize(usize::from(spec.allows_empty())));
+    }
+    let available = spec.available(n);
+    if let CycleSpec::Partition(parts) = spec {
+        if available.iter().all(|&m| m == 0) {
+            return Some(C::from_usize(0));
+        }
+        let mut remaining = n;
+        let mut total = C::from_usize(1);
+        for (length, &m) in available.iter().enumerate().filter(|(_, &m)| m > 0) {
+            total = total.mul_checked(&binomial(remaining, length * m)?)?;
+            remaining -= length * m;
+            for j in 1..=m {
+                total = total.mul_checked(&binomial(j * length - 1, length - 1)?)?;
+                total = total.mul_checked(&permutations(length - 1, length - 1)?)?;
+            }
+        }
+        debug_assert!(remaining == 0 && parts.iter().sum::<usize>() == n);
+        return Some(total);
+    }
+
+    let mut a = vec![C::from_usize(1)];
+    for r in 1..=n {
+        let mut total = C::from_usize(0);
+        for length in (2..=r).filter(|&length| available[length] > 0) {
+            let term = permutations::<C>(r - 1, length - 1)?.mul_checked(&a[r - length])?;
+            total = total.add_checked(&term)?;
+        }
+        a.push(total);
+    }
+    a.pop()
+}
+
+/// Count the derangements of a range of 0 to n (non-inclusive): the subfactorial !n.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: the number of derangements, or `None` if it does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, derangements_by_range};
+/// assert_eq!(count_derangements(4), Some(9));
+/// assert_eq!(count_derangements(8), Some(derangements_by_range(8).count() as u128));
+///
+/// // Overflow is detected rather than silently wrapping
+/// assert_eq!(count_derangements(35), None);
+/// ```
+pub fn count_derangements(n: usize) -> Option<u128> {
+    subfactorial(n)
+}
+
+/// Count the even derangements of a range of 0 to n (non-inclusive), as generated by
+/// `even_derangements_by_range`: (!n + (-1)^(n - 1) (n - 1)) / 2 for n > 0.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: the number of even derangements, or `None` if !n does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, count_even_derangements, count_odd_derangements};
+/// assert_eq!(count_even_derangements(4), Some(3));
+/// assert_eq!(count_even_derangements(5), Some(24));
+/// assert_eq!(count_even_derangements(0), Some(1));
+/// assert_eq!(count_even_derangements(30).zip(count_odd_derangements(30)).map(|(x, y)| x + y),
+///     count_derangements(30));
+/// ```
+pub fn count_even_derangements(n: usize) -> Option<u128> {
+    parity_derangements(n, false)
+}
+
+/// Count the odd derangements of a range of 0 to n (non-inclusive), as generated by
+/// `odd_derangements_by_range`: (!n - (-1)^(n - 1) (n - 1)) / 2 for n > 0.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: the number of odd derangements, or `None` if !n does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_odd_derangements;
+/// assert_eq!(count_odd_derangements(4), Some(6));
+/// assert_eq!(count_odd_derangements(5), Some(20));
+/// assert_eq!(count_odd_derangements(1), Some(0));
+/// ```
+pub fn count_odd_derangements(n: usize) -> Option<u128> {
+    parity_derangements(n, true)
+}
+
+/// Count the k-length derangements of a range of 0 to n (non-inclusive), as generated by
+/// `derangements(0..n, k)`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `k`: `usize` integer that determines how many elements each derangement should have
+///
+/// returns: the number of derangements, or `None` if it does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements_k, derangements};
+/// assert_eq!(count_derangements_k(4, 4), Some(9));
+/// assert_eq!(count_derangements_k(5, 3), Some(derangements(0..5usize, 3).count() as u128));
+/// assert_eq!(count_derangements_k(3, 4), Some(0));
+/// ```
+pub fn count_derangements_k(n: usize, k: usize) -> Option<u128> {
+    partial_derangements(n, k)
+}
+
+/// Count the permutations of a range of 0 to n (non-inclusive) with exactly m fixed points: the
+/// rencontres number D(n, m).
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `m`: usize integer that determines the number of fixed points
+///
+/// returns: the number of permutations, or `None` if it does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_rencontres;
+/// assert_eq!(count_rencontres(4, 0), Some(9));
+/// assert_eq!(count_rencontres(4, 1), Some(8));
+/// assert_eq!(count_rencontres(4, 3), Some(0));
+/// assert_eq!(count_rencontres(4, 4), Some(1));
+/// ```
+pub fn count_rencontres(n: usize, m: usize) -> Option<u128> {
+    rencontres(n, m)
+}
+
+/// Number of distinct arrangements of a multiset of values over the given positions, where value
+/// v is not at position v.
+///
+/// Choosing j positions that do hold their own value, inclusion-exclusion gives
+/// sum_j (-1)^j e_j (n - j)! / prod_v m_v!, where n is the number of positions, m_v is the
+/// multiplicity of value v and e_j is the elementary symmetric polynomial of the multiplicities of
+/// the values that are one of the positions.
+pub(crate) fn distinct_derangements<C: Count>(
+    positions: Range<usize>,
+    multiplicities: &[(usize, usize)],
+) -> Option<C> {
+    let n = positions.len();
+    let own = multiplicities
+        .iter()
+        .filter(|(value, _)| positions.contains(value))
+        .map(|(_, m)| *m)
+        .collect_vec();
+    let e = elementary_symmetric::<C>(&own)?;
+    let total = alternating_sum(
+        e.iter()
+            .enumerate()
+            .map(|(j, e_j)| e_j.mul_checked(&permutations(n - j, n - j)?)),
+    )?;
+    Some(
+        multiplicities
+            .iter()
+            .flat_map(|(_, m)| 2..=*m)
+            .fold(total, |acc, x| acc.div_exact(x)),
+    )
+}
+
+/// Count the distinct derangements of an iterable, as generated by `distinct_derangements`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to derange
+///
+/// returns: the number of distinct derangements, or `None` if an intermediate result does not fit
+/// in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_distinct_derangements, distinct_derangements};
+/// assert_eq!(count_distinct_derangements(vec![0usize, 1, 1].into_iter()), Some(1));
+/// assert_eq!(count_distinct_derangements(vec![0usize, 1, 7].into_iter()), Some(3));
+///
+/// let values = vec![0usize, 0, 1, 2, 2, 2, 4, 5];
+/// assert_eq!(count_distinct_derangements(values.clone().into_iter()),
+///     Some(distinct_derangements(values.into_iter()).count() as u128));
+/// ```
+pub fn count_distinct_derangements<I>(iter: I) -> Option<u128>
+where
+    I: Iterator,
+    I::Item: Ord + Clone,
+    usize: From<I::Item>,
+{
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let multiplicities = values
+        .iter()
+        .dedup_with_count()
+        .map(|(m, x)| (usize::from(x.clone()), m))
+        .collect_vec();
+    distinct_derangements(0..values.len(), &multiplicities)
+}
+
+/// Count the permutations of a range of 0 to n (non-inclusive) without any element in its window
+/// of w positions, as generated by `derangements_by_range_window`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `w`: usize integer that determines the width of the window of forbidden positions
+/// * `circular`: whether the window wraps around from the last position to the first one
+///
+/// returns: the number of permutations, or `None` if an intermediate result does not fit in a
+/// `u128`, or if the window is too wide to count in reasonable time: wider than 14 positions, or
+/// than 7 if it wraps around and covers at most half of the range
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, count_derangements_by_range_window};
+///
+/// // The ménage numbers
+/// assert_eq!(count_derangements_by_range_window(5, 2, true), Some(13));
+/// assert_eq!(count_derangements_by_range_window(10, 2, true), Some(439792));
+///
+/// // A window of one is a derangement
+/// assert_eq!(count_derangements_by_range_window(20, 1, false), count_derangements(20));
+///
+/// // Too wide to count
+/// assert_eq!(count_derangements_by_range_window(30, 15, false), None);
+/// ```
+pub fn count_derangements_by_range_window(n: usize, w: usize, circular: bool) -> Option<u128> {
+    window_derangements(n, w, circular)
+}
+
+/// Count the derangements of a range of 0 to n (non-inclusive) where every element moves at most
+/// d positions, as generated by `derangements_with_max_displacement_by_range`.
+///
+/// This uses a transfer matrix over the positions, of which the size grows exponentially with d,
+/// so it is limited to d up to 6 (or any d of at least n - 1, which allows every derangement):
+/// for 7 <= d < n - 1 the count is `None`, even when it would fit in a `u128`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `d`: usize integer that determines the largest displacement of an element
+///
+/// returns: the number of derangements, or `None` if it does not fit in a `u128` or d is too large
+/// to count
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, count_derangements_with_max_displacement};
+///
+/// // Moving by one means swapping neighbours, which gives 1 for every even n
+/// assert_eq!(count_derangements_with_max_displacement(100, 1), Some(1));
+/// assert_eq!(count_derangements_with_max_displacement(8, 7), count_derangements(8));
+/// assert_eq!(count_derangements_with_max_displacement(40, 39), count_derangements(40));
+/// assert!(count_derangements_with_max_displacement(30, 6).is_some());
+/// assert_eq!(count_derangements_with_max_displacement(30, 7), None);
+/// ```
+pub fn count_derangements_with_max_displacement(n: usize, d: usize) -> Option<u128> {
+    max_displacement_derangements(n, d)
+}
+
+/// Count the derangements of a range of 0 to n (non-inclusive) where every element moves at least
+/// d positions, as generated by `derangements_with_min_displacement_by_range`.
+///
+/// This uses rook numbers from a transfer matrix, of which the size grows exponentially with d,
+/// so it is limited to d up to 7 (or any d of at least n, which allows no derangement).
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `d`: usize integer that determines the smallest displacement of an element
+///
+/// returns: the number of derangements, or `None` if an intermediate result does not fit in a
+/// `u128` or d is too large to count
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, count_derangements_with_min_displacement};
+/// assert_eq!(count_derangements_with_min_displacement(6, 2), Some(29));
+/// assert_eq!(count_derangements_with_min_displacement(20, 1), count_derangements(20));
+/// assert_eq!(count_derangements_with_min_displacement(30, 15), None);
+/// ```
+pub fn count_derangements_with_min_displacement(n: usize, d: usize) -> Option<u128> {
+    min_displacement_derangements(n, d)
+}
+
+/// Count the involutions of a range of 0 to n (non-inclusive) without fixed points, as generated
+/// by `involutive_derangements_by_range`: the double factorial (n - 1)!! for even n, and 0 for odd n.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: the number of derangements, or `None` if it does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_involutive_derangements, involutive_derangements_by_range};
+/// assert_eq!(count_involutive_derangements(6), Some(15));
+/// assert_eq!(count_involutive_derangements(7), Some(0));
+/// assert_eq!(count_involutive_derangements(12),
+///     Some(involutive_derangements_by_range(12).count() as u128));
+/// ```
+pub fn count_involutive_derangements(n: usize) -> Option<u128> {
+    involutive_derangements(n)
+}
+
+/// Count the derangements of a range of 0 to n (non-inclusive) with the cycle lengths allowed by
+/// `spec`, as generated by `derangements_by_cycle_type`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `spec`: the `CycleSpec` with the allowed cycle lengths
+///
+/// returns: the number of derangements, or `None` if an intermediate result does not fit in a
+/// `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_derangements, count_derangements_by_cycle_type, CycleSpec};
+///
+/// // A single cycle of n elements can be arranged in (n - 1)! ways
+/// assert_eq!(count_derangements_by_cycle_type(10, CycleSpec::SingleCycle), Some(362880));
+/// assert_eq!(count_derangements_by_cycle_type(6, CycleSpec::Partition(vec![3, 3])), Some(40));
+/// assert_eq!(count_derangements_by_cycle_type(20, CycleSpec::MinLength(2)), count_derangements(20));
+/// ```
+pub fn count_derangements_by_cycle_type(n: usize, spec: CycleSpec) -> Option<u128> {
+    cycle_type_derangements(n, &spec)
+}
+
+/// Count the permutations of a range of 0 to n (non-inclusive) that are a single cycle, as generated
+/// by `cyclic_permutations_by_range`: (n - 1)! for n >= 2, and 0 otherwise.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+///
+/// returns: the number of cyclic permutations, or `None` if it does not fit in a `u128`
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_cyclic_permutations;
+/// assert_eq!(count_cyclic_permutations(5), Some(24));
+/// assert_eq!(count_cyclic_permutations(1), Some(0));
+/// assert_eq!(count_cyclic_permutations(40), None);
+/// ```
+pub fn count_cyclic_permutations(n: usize) -> Option<u128> {
+    cycle_type_derangements(n, &CycleSpec::SingleCycle)
+}
+
+/// Count the k-permutations of an iterable as generated by `restricted_permutations`, without
+/// enumerating them.
+///
+/// This computes the permanent of the matrix of allowed (value, position) pairs: with the rook
+/// numbers of the forbidden pairs if there are few of them, and with Ryser's formula otherwise.
+/// Ryser's formula takes 2^r steps for r values that are forbidden somewhere, so with more than 22
+/// of those the count is only found if the forbidden pairs split into small enough groups.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `restrict`: the iterable of items to use as restriction
+///
+/// returns: the number of permutations, or `None` if an intermediate result does not fit in a
+/// `u128` or if the restriction is too large to count
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{count_restricted_permutations, restricted_permutations};
+/// assert_eq!(count_restricted_permutations(vec![1, 0, 2, 2].into_iter(), 3, vec![1, 0, 2].into_iter()),
+///     Some(8));
+/// assert_eq!(count_restricted_permutations(1..9, 6, 3..9),
+///     Some(restricted_permutations(1..9, 6, 3..9).count() as u128));
+/// ```
+pub fn count_restricted_permutations<I>(iter: I, k: usize, restrict: I) -> Option<u128>
+where
+    I: Iterator,
+    I::Item: PartialEq,
+{
+    let values = iter.collect_vec();
+    permanent(&Restriction::from_sequence(
+        &values,
+        k,
+        &restrict.collect_vec(),
+    ))
+}
+
+/// Count the k-permutations of an iterable as generated by
+/// `restricted_permutations_by_map_index`, without enumerating them.
+///
+/// This is computed in the same way as `count_restricted_permutations`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `restrict`: `HashMap<usize, Vec<I::Item>>`, indicating which elements can not be at an index
+///
+/// returns: the number of permutations, or `None` if an intermediate result does not fit in a
+/// `u128` or if the restriction is too large to count
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use derangements::count_restricted_permutations_by_map_index;
+///
+/// // Exclude from index 0 the values 0 and 1, and from index 1 the values 1 and 2.
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// assert_eq!(count_restricted_permutations_by_map_index(vec![0, 1, 2, 3].into_iter(), 3, restrict),
+///     Some(6));
+/// ```
+pub fn count_restricted_permutations_by_map_index<I>(
+    iter: I,
+    k: usize,
+    restrict: HashMap<usize, Vec<I::Item>>,
+) -> Option<u128>
+where
+    I: Iterator,
+    I::Item: PartialEq,
+{
+    let values = iter.collect_vec();
+    permanent(&Restriction::from_map_index(&values, k, &restrict))
+}
+
+/// Count the k-permutations of an iterable as generated by
+/// `restricted_permutations_by_map_value`, without enumerating them.
+///
+/// This is computed in the same way as `count_restricted_permutations`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `restrict`: `HashMap<I::Item, Vec<usize>>`, indicating at which indices an element can't be
+///
+/// returns: the number of permutations, or `None` if an intermediate result does not fit in a
+/// `u128` or if the restriction is too large to count
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use derangements::count_restricted_permutations_by_map_value;
+///
+/// // Exclude from value 0 from indices 0 and 1, and value 1 from indices 1 and 2.
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// assert_eq!(count_restricted_permutations_by_map_value(vec![0, 1, 2, 3].into_iter(), 3, restrict),
+///     Some(6));
+/// ```
+pub fn count_restricted_permutations_by_map_value<I>(
+    iter: I,
+    k: usize,
+    restrict: HashMap<I::Item, Vec<usize>>,
+) -> Option<u128>
+where
+    I: Iterator,
+    I::Item: Eq + Hash,
+{
+    let values = iter.collect_vec();
+    permanent(&Restriction::from_map_value(&values, k, &restrict))
+}
+
+/// Arbitrary-precision version of `count_derangements`.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_derangements_big;
+/// assert_eq!(count_derangements_big(4), 9u32.into());
+/// ```
+#[cfg(feature = "bigint")]
+pub fn count_derangements_big(n: usize) -> BigUint {
+    subfactorial(n).unwrap()
+}
+
+/// Arbitrary-precision version of `count_derangements_k`.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_derangements_k_big;
+/// assert_eq!(count_derangements_k_big(4, 2), 7u32.into());
+/// ```
+#[cfg(feature = "bigint")]
+pub fn count_derangements_k_big(n: usize, k: usize) -> BigUint {
+    partial_derangements(n, k).unwrap()
+}
+
+/// Arbitrary-precision version of `count_rencontres`.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::count_rencontres_big;
+/// assert_eq!(count_rencontres_big(4, 1), 8u32.into());
+/// ```
+#[cfg(feature = "bigint")]
+pub fn count_rencontres_big(n: usize, m: usize) -> BigUint {
+    rencontres(n, m).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        distinct_derangements, fast_permutations, restricted_permutations,
+        restricted_permutations_by_map_index, restricted_permutations_by_map_value,
+    };
+
+    fn fixed_points(x: &[usize]) -> usize {
+        x.iter().enumerate().filter(|(i, v)| i == *v).count()
+    }
+
+    #[test]
+    fn test_count_brute_force() {
+        for n in 0..=8 {
+            for k in 0..=n {
+                let brute = fast_permutations(0..n, k)
+                    .filter(|x| fixed_points(x) == 0)
+                    .count();
+                assert_eq!(count_derangements_k(n, k), Some(brute as u128));
+            }
+            assert_eq!(count_derangements(n), count_derangements_k(n, n));
+            for m in 0..=n {
+                let brute = fast_permutations(0..n, n)
+                    .filter(|x| fixed_points(x) == m)
+                    .count();
+                assert_eq!(count_rencontres(n, m), Some(brute as u128));
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_distinct() {
+        // All multisets of up to 7 values out of 0..4, plus a value that is never an index
+        for n in 0..=7 {
+            for values in (0..5usize)
+                .map(|x| if x == 4 { 9 } else { x })
+                .combinations_with_replacement(n)
+            {
+                assert_eq!(
+                    count_distinct_derangements(values.clone().into_iter()),
+                    Some(distinct_derangements(values.into_iter()).count() as u128)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_restricted() {
+        for n in 0..=7 {
+            let by_index: HashMap<usize, Vec<usize>> =
+                HashMap::from_iter((0..n).map(|i| (i, vec![i, (i * 3 + 1) % n, n - 1 - i])));
+            let by_value: HashMap<usize, Vec<usize>> =
+                HashMap::from_iter((0..n).map(|i| (i, vec![(i + 1) % n, (i * 5) % n])));
+            for k in 0..=n + 1 {
+                let values = (0..n).rev().collect_vec();
+                let restrict = (0..n).map(|i| (i * 2) % n).collect_vec();
+                assert_eq!(
+                    count_restricted_permutations(0..n, k, 0..n),
+                    Some(restricted_permutations(0..n, k, 0..n).count() as u128)
+                );
+                assert_eq!(
+                    count_restricted_permutations(
+                        values.clone().into_iter(),
+                        k,
+                        restrict.clone().into_iter()
+                    ),
+                    Some(
+                        restricted_permutations(
+                            values.clone().into_iter(),
+                            k,
+                            restrict.clone().into_iter()
+                        )
+                        .count() as u128
+                    )
+                );
+                assert_eq!(
+                    count_restricted_permutations_by_map_index(0..n, k, by_index.clone()),
+                    Some(
+                        restricted_permutations_by_map_index(0..n, k, by_index.clone()).count()
+                            as u128
+                    )
+                );
+                assert_eq!(
+                    count_restricted_permutations_by_map_value(0..n, k, by_value.clone()),
+                    Some(
+                        restricted_permutations_by_map_value(0..n, k, by_value.clone()).count()
+                            as u128
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_overflow() {
+        // !n = n * !(n - 1) + (-1)^n, which is the largest subfactorial to fit in a u128 at n = 34
+        let last = count_derangements(34).unwrap();
+        assert_eq!(count_derangements(33).unwrap() * 34 + 1, last);
+        assert_eq!(count_derangements(35), None);
+        assert_eq!(count_derangements_k(40, 5), Some(69_583_859));
+        assert_eq!(count_rencontres(35, 1), None);
+        assert_eq!(count_rencontres(100, 98), Some(4950));
+
+        // C(130, 65) fits, even though C(130, 64) * 66 does not
+        let choose = binomial::<u128>(130, 65).unwrap();
+        assert_eq!(choose, binomial::<u128>(129, 64).unwrap() * 2);
+        assert_eq!(count_rencontres(130, 129), Some(0));
+        assert_eq!(count_rencontres(132, 130), binomial(132, 2));
+        assert_eq!(count_rencontres(131, 65), None);
+
+        // The sum of the even terms of !34 alone, about cosh(1) 34!, would not fit
+        let forbidden = vec![1; 34];
+        assert_eq!(forbidden_permutations(34, 34, &forbidden), Some(last));
+        let mut forbidden = vec![1; 34];
+        forbidden[33] = 0;
+        assert_eq!(
+            forbidden_permutations::<u128>(34, 34, &forbidden),
+            Some(last + count_derangements(33).unwrap())
+        );
+        assert_eq!(
+            count_distinct_derangements(0..34usize),
+            count_derangements(34)
+        );
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn test_count_big() {
+        let last = count_derangements(34).unwrap();
+        assert_eq!(
+            count_derangements_big(35),
+            BigUint::from(last) * 35u32 - 1u32
+        );
+        for n in 0..=30 {
+            for k in 0..=n {
+                assert_eq!(
+                    count_derangements_k_big(n, k),
+                    count_derangements_k(n, k).unwrap().into()
+                );
+            }
+            assert_eq!(
+                count_rencontres_big(n, n / 2),
+                count_rencontres(n, n / 2).unwrap().into()
+            );
+        }
+    }
+}
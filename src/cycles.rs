@@ -0,0 +1,705 @@
+use crate::counting;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::iter::FusedIterator;
+
+/// Which cycle lengths a derangement may have, for `derangements_by_cycle_type`.
+///
+/// Cycles of length 1 are fixed points, so they are never allowed: a minimum length below 2 still
+/// gives all derangements.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{derangements_by_cycle_type, CycleSpec};
+///
+/// // A gift circle where nobody gives to the person that gives to them
+/// assert_eq!(derangements_by_cycle_type(6, CycleSpec::MinLength(3)).count(), 160);
+///
+/// // Exactly two pairs and a triple
+/// assert_eq!(derangements_by_cycle_type(7, CycleSpec::Partition(vec![2, 2, 3])).count(), 210);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CycleSpec {
+    /// Every cycle has at least this length
+    MinLength(usize),
+    /// Every cycle has at most this length
+    MaxLength(usize),
+    /// The cycle lengths are exactly these, in any order
+    Partition(Vec<usize>),
+    /// All elements are in one cycle
+    SingleCycle,
+}
+
+impl CycleSpec {
+    /// How many more cycles of each length (up to n) can be used, where `usize::MAX` means any
+    /// number of them.
+    pub(crate) fn available(&self, n: usize) -> Vec<usize> {
+        let mut available = vec![0; n + 1];
+        match self {
+            CycleSpec::MinLength(m) => {
+                available
+                    .iter_mut()
+                    .skip((*m).max(2))
+                    .for_each(|x| *x = usize::MAX);
+            }
+            CycleSpec::MaxLength(m) => {
+                let lengths = available.iter_mut().take(m.saturating_add(1)).skip(2);
+                lengths.for_each(|x| *x = usize::MAX);
+            }
+            CycleSpec::Partition(parts) => {
+                if parts.iter().sum::<usize>() == n && parts.iter().all(|&x| x >= 2) {
+                    for &part in parts {
+                        available[part] += 1;
+                    }
+                }
+            }
+            CycleSpec::SingleCycle => {
+                if n >= 2 {
+                    available[n] = 1;
+                }
+            }
+        }
+        available
+    }
+
+    /// Whether the derangement of 0 elements, which has no cycles, satisfies the spec
+    pub(crate) fn allows_empty(&self) -> bool {
+        match self {
+            CycleSpec::MinLength(_) | CycleSpec::MaxLength(_) => true,
+            CycleSpec::Partition(parts) => parts.is_empty(),
+            CycleSpec::SingleCycle => false,
+        }
+    }
+}
+
+/// A choice in the search: to close the current cycle, or to extend it with an element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Step {
+    Close,
+    Extend(usize),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "CycleTypeRangeState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct CycleTypeRangeIterator {
+    spec: CycleSpec,
+    available: Vec<usize>,
+    splittable: Vec<bool>,
+    used: Vec<bool>,
+    order: Vec<usize>,
+    starts: Vec<usize>,
+    open: bool,
+    steps: Vec<Step>,
+    done: bool,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `CycleTypeRangeIterator`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct CycleTypeRangeState {
+    spec: CycleSpec,
+    available: Vec<usize>,
+    splittable: Vec<bool>,
+    used: Vec<bool>,
+    order: Vec<usize>,
+    starts: Vec<usize>,
+    open: bool,
+    steps: Vec<Step>,
+    done: bool,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<CycleTypeRangeState> for CycleTypeRangeIterator {
+    type Error = &'static str;
+
+    fn try_from(state: CycleTypeRangeState) -> Result<Self, Self::Error> {
+        // Replay the steps of the search, which have to be steps that it could have taken
+        let n = state.used.len();
+        let mut iter = CycleTypeRangeIterator::new(n, state.spec);
+        if n > 0 {
+            iter.start_cycle(0);
+        }
+        for &step in &state.steps {
+            if !iter.open {
+                return Err("steps after the last cycle is closed");
+            }
+            let (length, free) = (iter.length(), iter.free());
+            let feasible = match step {
+                Step::Close => iter.available[length] > 0 && iter.splittable[free],
+                Step::Extend(x) => {
+                    x < n && !iter.used[x] && free > 0 && iter.can_finish(length + 1, free - 1)
+                }
+            };
+            if !feasible {
+                return Err("steps that the search can't take");
+            }
+            iter.apply(step);
+        }
+        if iter.available != state.available
+            || iter.splittable != state.splittable
+            || iter.used != state.used
+            || iter.order != state.order
+            || iter.starts != state.starts
+            || iter.open != state.open
+            || (iter.open && !state.done)
+        {
+            return Err("cycles don't match the steps of the search");
+        }
+        let total = iter.remaining;
+        if state.done && state.remaining.is_some_and(|remaining| remaining > 0)
+            || !state.done && state.remaining == Some(0)
+            || total
+                .zip(state.remaining)
+                .is_some_and(|(total, x)| x > total)
+        {
+            return Err("remaining derangements don't match the search");
+        }
+        iter.done = state.done;
+        iter.remaining = state.remaining;
+        Ok(iter)
+    }
+}
+
+/// Derange the range of 0 to n (non-inclusive) such that its cycles have the lengths allowed by
+/// `spec`.
+///
+/// The derangements are built directly from their cycles: each cycle starts at the smallest
+/// element that is not in a cycle yet, and is extended with larger free elements until it is
+/// closed at an allowed length. A cycle is only extended or closed if the free elements can still
+/// be split into cycles of allowed lengths.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `spec`: the `CycleSpec` with the allowed cycle lengths
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{derangements_by_cycle_type, CycleSpec};
+/// assert_equal(derangements_by_cycle_type(4, CycleSpec::MaxLength(2)),
+///     [[1, 0, 3, 2], [2, 3, 0, 1], [3, 2, 1, 0]]);
+/// assert_equal(derangements_by_cycle_type(4, CycleSpec::SingleCycle),
+///     [[1, 2, 3, 0], [1, 3, 0, 2], [2, 3, 1, 0], [2, 0, 3, 1], [3, 2, 0, 1], [3, 0, 1, 2]]);
+/// ```
+pub fn derangements_by_cycle_type(n: usize, spec: CycleSpec) -> CycleTypeRangeIterator {
+    let mut iter = CycleTypeRangeIterator::new(n, spec);
+    if n == 0 {
+        iter.done = !iter.spec.allows_empty();
+    } else {
+        iter.start_cycle(0);
+        iter.done = !iter.search(None);
+    }
+    iter
+}
+
+impl CycleTypeRangeIterator {
+    /// The search before any cycle is started
+    fn new(n: usize, spec: CycleSpec) -> Self {
+        let available = spec.available(n);
+        let mut splittable = vec![true; n + 1];
+        if !matches!(spec, CycleSpec::Partition(_)) {
+            // With exact parts the free elements always add up to the parts that are left
+            for r in 1..=n {
+                splittable[r] =
+                    (2..=r).any(|length| available[length] > 0 && splittable[r - length]);
+            }
+        }
+        CycleTypeRangeIterator {
+            remaining: counting::cycle_type_derangements(n, &spec),
+            spec,
+            available,
+            splittable,
+            used: vec![false; n],
+            order: Vec::with_capacity(n),
+            starts: vec![],
+            open: false,
+            steps: vec![],
+            done: false,
+        }
+    }
+
+    fn free(&self) -> usize {
+        self.used.len() - self.order.len()
+    }
+
+    fn length(&self) -> usize {
+        self.order.len() - self.starts.last().unwrap()
+    }
+
+    fn start_cycle(&mut self, x: usize) {
+        self.starts.push(self.order.len());
+        self.order.push(x);
+        self.used[x] = true;
+        self.open = true;
+    }
+
+    /// Whether a cycle of the given length can be closed some time, with the given number of free
+    /// elements left to extend it
+    fn can_finish(&self, length: usize, free: usize) -> bool {
+        (length..=length + free)
+            .any(|total| self.available[total] > 0 && self.splittable[free - (total - length)])
+    }
+
+    /// The first step after `after` (or the first step at all) that keeps the search feasible
+    fn next_step(&self, after: Option<Step>) -> Option<Step> {
+        let (length, free) = (self.length(), self.free());
+        let first = match after {
+            None => {
+                if self.available[length] > 0 && self.splittable[free] {
+                    return Some(Step::Close);
+                }
+                0
+            }
+            Some(Step::Close) => 0,
+            Some(Step::Extend(x)) => x + 1,
+        };
+        if free == 0 || !self.can_finish(length + 1, free - 1) {
+            return None;
+        }
+        (first..self.used.len())
+            .find(|&x| !self.used[x])
+            .map(Step::Extend)
+    }
+
+    fn apply(&mut self, step: Step) {
+        match step {
+            Step::Close => {
+                let length = self.length();
+                if self.available[length] != usize::MAX {
+                    self.available[length] -= 1;
+                }
+                match self.used.iter().position(|&x| !x) {
+                    Some(x) => self.start_cycle(x),
+                    None => self.open = false,
+                }
+            }
+            Step::Extend(x) => {
+                self.order.push(x);
+                self.used[x] = true;
+            }
+        }
+        self.steps.push(step);
+    }
+
+    fn undo(&mut self, step: Step) {
+        match step {
+            Step::Close => {
+                if self.open {
+                    let x = self.order.pop().unwrap();
+                    self.used[x] = false;
+                    self.starts.pop();
+                }
+                self.open = true;
+                let length = self.length();
+                if self.available[length] != usize::MAX {
+                    self.available[length] += 1;
+                }
+            }
+            Step::Extend(x) => {
+                self.order.pop();
+                self.used[x] = false;
+            }
+        }
+    }
+
+    /// Search depth-first for the next complete derangement, trying the steps after `after` first
+    fn search(&mut self, mut after: Option<Step>) -> bool {
+        loop {
+            if let Some(step) = self.next_step(after) {
+                self.apply(step);
+                if !self.open {
+                    return true;
+                }
+                after = None;
+            } else if let Some(step) = self.steps.pop() {
+                self.undo(step);
+                after = Some(step);
+            } else {
+                return false;
+            }
+        }
+    }
+
+    fn derangement(&self) -> Vec<usize> {
+        let mut derangement = vec![0; self.order.len()];
+        let ends = self
+            .starts
+            .iter()
+            .skip(1)
+            .copied()
+            .chain([self.order.len()]);
+        for (&start, end) in self.starts.iter().zip(ends) {
+            let cycle = &self.order[start..end];
+            for (i, &x) in cycle.iter().enumerate() {
+                derangement[x] = cycle[(i + 1) % cycle.len()];
+            }
+        }
+        derangement
+    }
+}
+
+impl Iterator for CycleTypeRangeIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.derangement();
+        self.done = match self.steps.pop() {
+            Some(step) => {
+                self.undo(step);
+                !self.search(Some(step))
+            }
+            None => true,
+        };
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl CycleTypeRangeIterator {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+}
+
+impl FusedIterator for CycleTypeRangeIterator {}
+
+/// Permute the range of 0 to n (non-inclusive) into a single cycle of all n elements.
+///
+/// These are the (n - 1)! derangements of `derangements_by_cycle_type` with `CycleSpec::SingleCycle`:
+/// the cycle starts at 0 and visits the other elements in each of their orders. A single element
+/// is a fixed point rather than a derangement, so there are none for n < 2.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+///
+/// returns: iterable with the cyclic permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::cyclic_permutations_by_range;
+/// assert_equal(cyclic_permutations_by_range(3), [[1, 2, 0], [2, 0, 1]]);
+/// assert_eq!(cyclic_permutations_by_range(6).count(), 120);
+/// ```
+pub fn cyclic_permutations_by_range(n: usize) -> CycleTypeRangeIterator {
+    derangements_by_cycle_type(n, CycleSpec::SingleCycle)
+}
+
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "CyclicPermutationsState<I>"
+    )
+)]
+pub struct CyclicPermutations<I: Iterator> {
+    values: Vec<I::Item>,
+    indices: CycleTypeRangeIterator,
+}
+
+/// The fields of a deserialized `CyclicPermutations`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct CyclicPermutationsState<I: Iterator> {
+    values: Vec<I::Item>,
+    indices: CycleTypeRangeIterator,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<CyclicPermutationsState<I>> for CyclicPermutations<I> {
+    type Error = &'static str;
+
+    fn try_from(state: CyclicPermutationsState<I>) -> Result<Self, Self::Error> {
+        if state.values.len() != state.indices.used.len()
+            || state.indices.spec != CycleSpec::SingleCycle
+        {
+            return Err("values don't match the cycles");
+        }
+        Ok(CyclicPermutations {
+            values: state.values,
+            indices: state.indices,
+        })
+    }
+}
+
+impl<I> Clone for CyclicPermutations<I>
+where
+    I: Clone + Iterator,
+    I::Item: Clone,
+{
+    clone_fields!(values, indices);
+}
+
+impl<I> Debug for CyclicPermutations<I>
+where
+    I: Iterator + Debug,
+    I::Item: Debug,
+{
+    debug_fmt_fields!(CyclicPermutations, values, indices);
+}
+
+/// Permute all elements of an iterable along a single cycle of all positions.
+///
+/// The item at position i moves to the position that is next in the cycle, so as for
+/// `permutations_with_fixed_points`, the cycles are between positions in the input.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+///
+/// returns: iterable with the cyclic permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::cyclic_permutations;
+/// assert_equal(cyclic_permutations(vec!["a", "b", "c"].into_iter()),
+///     [["b", "c", "a"], ["c", "a", "b"]]);
+/// ```
+pub fn cyclic_permutations<I>(iter: I) -> CyclicPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    let values = Vec::from_iter(iter);
+    let indices = cyclic_permutations_by_range(values.len());
+    CyclicPermutations { values, indices }
+}
+
+impl<I> Iterator for CyclicPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let indices = self.indices.next()?;
+        Some(indices.iter().map(|&i| self.values[i].clone()).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.indices.size_hint()
+    }
+}
+
+impl<I> CyclicPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.indices.remaining()
+    }
+}
+
+impl<I> FusedIterator for CyclicPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derangements_by_range;
+    use itertools::{assert_equal, Itertools};
+    use std::collections::HashSet;
+
+    fn cycle_lengths(x: &[usize]) -> Vec<usize> {
+        let mut seen = vec![false; x.len()];
+        let mut lengths = vec![];
+        for start in 0..x.len() {
+            let mut length = 0;
+            let mut i = start;
+            while !seen[i] {
+                seen[i] = true;
+                i = x[i];
+                length += 1;
+            }
+            if length > 0 {
+                lengths.push(length);
+            }
+        }
+        lengths.sort_unstable();
+        lengths
+    }
+
+    fn check(n: usize, spec: CycleSpec, keep: impl Fn(&[usize]) -> bool) {
+        let expected = derangements_by_range(n)
+            .filter(|x| keep(&cycle_lengths(x)))
+            .sorted()
+            .collect_vec();
+        let iter = derangements_by_cycle_type(n, spec.clone());
+        assert_eq!(
+            iter.remaining(),
+            Some(expected.len() as u128),
+            "{n} {spec:?}"
+        );
+        assert_equal(iter.sorted(), expected);
+    }
+
+    #[test]
+    fn test_cycle_type_brute_force() {
+        for n in 0..=8 {
+            for m in 0..=n + 1 {
+                check(n, CycleSpec::MinLength(m), |x| x.iter().all(|&l| l >= m));
+                check(n, CycleSpec::MaxLength(m), |x| x.iter().all(|&l| l <= m));
+            }
+            check(n, CycleSpec::SingleCycle, |x| x.len() == 1);
+        }
+    }
+
+    #[test]
+    fn test_cycle_type_partitions() {
+        for n in 0..=8usize {
+            for parts in [
+                vec![],
+                vec![n],
+                vec![2, 2, 3],
+                vec![3, 2, 3],
+                vec![1, n.saturating_sub(1)],
+            ] {
+                let sorted = parts.iter().copied().sorted().collect_vec();
+                check(n, CycleSpec::Partition(parts), |x| x == sorted);
+            }
+        }
+        assert_eq!(
+            derangements_by_cycle_type(3, CycleSpec::Partition(vec![4])).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_cycle_type_order_is_unique() {
+        let all = derangements_by_cycle_type(7, CycleSpec::MinLength(0)).collect_vec();
+        assert_eq!(all.len(), 1854);
+        assert!(all.iter().all_unique());
+    }
+
+    #[test]
+    fn test_cyclic_permutations() {
+        for n in 0..=8 {
+            let derangements = derangements_by_range(n).collect::<HashSet<_>>();
+            let iter = cyclic_permutations_by_range(n);
+            assert_eq!(
+                iter.remaining(),
+                Some((1..n).product::<usize>() as u128 * u128::from(n >= 2))
+            );
+            for x in iter {
+                assert_eq!(cycle_lengths(&x), [n]);
+                assert!(derangements.contains(&x));
+            }
+        }
+        assert_eq!(cyclic_permutations_by_range(1).count(), 0);
+    }
+
+    #[test]
+    fn test_cyclic_permutations_iterable() {
+        let values = vec!['a', 'b', 'b', 'c', 'd'];
+        let expected = cyclic_permutations_by_range(values.len())
+            .map(|x| x.into_iter().map(|i| values[i]).collect_vec())
+            .collect_vec();
+        let iter = cyclic_permutations(values.clone().into_iter());
+        assert_eq!(iter.remaining(), Some(24));
+        assert_equal(iter, expected);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = derangements_by_cycle_type(8, CycleSpec::MinLength(3));
+        iter.nth(500);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: CycleTypeRangeIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let resume = |x| serde_json::from_value::<CycleTypeRangeIterator>(x).is_err();
+        for (n, spec) in [
+            (0, CycleSpec::SingleCycle),
+            (6, CycleSpec::MaxLength(3)),
+            (7, CycleSpec::Partition(vec![2, 2, 3])),
+        ] {
+            let mut iter = derangements_by_cycle_type(n, spec);
+            loop {
+                assert!(!resume(serde_json::to_value(&iter).unwrap()));
+                if iter.next().is_none() {
+                    break;
+                }
+            }
+        }
+
+        let mut corrupted = serde_json::to_value(cyclic_permutations_by_range(0)).unwrap();
+        corrupted["steps"] = serde_json::json!(["Close"]);
+        assert!(resume(corrupted));
+
+        let mut iter = derangements_by_cycle_type(8, CycleSpec::MinLength(3));
+        iter.nth(500);
+        let state = serde_json::to_value(&iter).unwrap();
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 0.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 100000.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["spec"] = serde_json::json!({"MinLength": 4});
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["steps"][0] = serde_json::json!({"Extend": 8});
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["order"][1] = 0.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["available"][3] = 0.into();
+        assert!(resume(corrupted));
+
+        let mut iter = cyclic_permutations(vec!["a", "b", "c", "d"].into_iter());
+        iter.next();
+        let mut corrupted = serde_json::to_value(&iter).unwrap();
+        corrupted["values"] = vec!["a", "b"].into();
+        assert!(
+            serde_json::from_value::<CyclicPermutations<std::vec::IntoIter<String>>>(corrupted)
+                .is_err()
+        );
+    }
+}
@@ -1,33 +1,31 @@
-use crate::fast_permutations;
-use crate::fast_permutations::FastPermutations;
+use crate::counting;
+use crate::counting::count_distinct_derangements;
+use crate::fast_permutations::{distinct_permutations, DistinctPermutations};
+use crate::restriction::{perfect_matchings, PerfectMatchings, Restriction};
+use crate::shard::Shard;
+use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::iter::FusedIterator;
 
-macro_rules! clone_fields {  // Note: copied from Itertools - need to attribute before release - or just directly write the clone func
-    ($($field:ident),*) => {
-        #[inline]
-        fn clone(&self) -> Self {
-            Self {
-                $($field: self.$field.clone(),)*
-            }
-        }
-    }
-}
-macro_rules! debug_fmt_fields {  // Note: copied from Itertools - need to attribute before release - or just directly write the fmt func
-    ($tyname:ident, $($($field:tt).+),*) => {
-        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-            f.debug_struct(stringify!($tyname))
-                $(
-              .field(stringify!($($field).+), &self.$($field).+)
-              )*
-              .finish()
-        }
-    }
-}
-
 #[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "DerangementsState<I>"
+    )
+)]
 pub struct Derangements<I: Iterator> {
-    permutations: FastPermutations<I>,
+    values: Vec<I::Item>,
+    fixed: Vec<usize>,
+    matchings: PerfectMatchings,
+    remaining: Option<u128>,
 }
 
 impl<I> Clone for Derangements<I>
@@ -35,7 +33,7 @@ where
     I: Clone + Iterator,
     I::Item: Clone,
 {
-    clone_fields!(permutations);
+    clone_fields!(values, fixed, matchings, remaining);
 }
 
 impl<I> Debug for Derangements<I>
@@ -43,11 +41,51 @@ where
     I: Iterator + Debug,
     I::Item: Debug,
 {
-    debug_fmt_fields!(Derangements, permutations);
+    debug_fmt_fields!(Derangements, values, fixed, matchings, remaining);
+}
+
+/// The fields of a deserialized `Derangements`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct DerangementsState<I: Iterator> {
+    values: Vec<I::Item>,
+    fixed: Vec<usize>,
+    matchings: PerfectMatchings,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<DerangementsState<I>> for Derangements<I> {
+    type Error = &'static str;
+
+    fn try_from(state: DerangementsState<I>) -> Result<Self, Self::Error> {
+        let restriction = state.matchings.restriction();
+        let (n, k) = (restriction.values(), restriction.positions());
+        if state.values.len() != n || state.fixed.len() != n {
+            return Err("values of a different length than the restriction");
+        }
+        if let (Some(remaining), Some(total)) = (state.remaining, completions(&state.fixed, k, &[]))
+        {
+            if remaining > total {
+                return Err("more derangements remaining than there are in total");
+            }
+        }
+        Ok(Derangements {
+            values: state.values,
+            fixed: state.fixed,
+            matchings: state.matchings,
+            remaining: state.remaining,
+        })
+    }
 }
 
 /// Derange k or all elements of an iterable.
 ///
+/// Each derangement is built directly by backtracking, so they come in lexicographic order of the
+/// sorted input. Up to version 0.2.0 they were filtered from the permutations and came in the
+/// order of `fast_permutations` instead.
+///
 /// # Arguments
 ///
 /// * `iterable`: the iterable of items to derange
@@ -60,16 +98,16 @@ where
 /// ```
 /// use itertools::{assert_equal, Itertools};
 /// use derangements::derangements;
-/// assert_equal(derangements(vec![0usize, 1, 2].into_iter(), 3), [[2, 0, 1], [1, 2, 0]]);
+/// assert_equal(derangements(vec![0usize, 1, 2].into_iter(), 3), [[1, 2, 0], [2, 0, 1]]);
 ///
 /// // There can be repeated values in the input, which will not be deduplicated
 /// assert_equal(derangements(vec![0usize, 1, 1].into_iter(), 3), [[1, 0, 1], [1, 0, 1]]);
 ///
 /// // The length of the derangements can be shorter than the input iterable
-/// assert_equal(derangements(vec![0usize, 1, 2].into_iter(), 2), [[2, 0], [1, 0], [1, 2]]);
+/// assert_equal(derangements(vec![0usize, 1, 2].into_iter(), 2), [[1, 0], [1, 2], [2, 0]]);
 ///
 /// // There can be values that are outside the range of the indices
-/// assert_equal(derangements(vec![0usize, 1, 7].into_iter(), 3), [[7, 0, 1], [1, 0, 7], [1, 7, 0]]);
+/// assert_equal(derangements(vec![0usize, 1, 7].into_iter(), 3), [[1, 0, 7], [1, 7, 0], [7, 0, 1]]);
 /// ```
 pub fn derangements<I>(iter: I, k: usize) -> Derangements<I>
 where
@@ -77,47 +115,186 @@ where
     I::Item: Clone + Ord,
     usize: From<I::Item>,
 {
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let fixed = values.iter().map(|x| usize::from(x.clone())).collect_vec();
+    let matchings = perfect_matchings(Restriction::from_indices(&values, k));
+    let remaining = completions(&fixed, k, &[]);
     Derangements {
-        permutations: fast_permutations(iter, k),
+        values,
+        fixed,
+        matchings,
+        remaining,
+    }
+}
+
+/// Number of derangements that start with the values at the indices of `prefix`.
+fn completions(fixed: &[usize], k: usize, prefix: &[usize]) -> Option<u128> {
+    let mut used = vec![false; fixed.len()];
+    for &index in prefix {
+        used[index] = true;
+    }
+
+    // Each index can only be blocked by the values that equal it
+    let mut forbidden = vec![0; k.saturating_sub(prefix.len())];
+    for (index, &x) in fixed.iter().enumerate() {
+        if !used[index] && (prefix.len()..k).contains(&x) {
+            forbidden[x - prefix.len()] += 1;
+        }
+    }
+    counting::forbidden_permutations(fixed.len() - prefix.len(), k - prefix.len(), &forbidden)
+}
+
+impl<I: Iterator> Derangements<I> {
+    /// Restrict the derangements that are left to one of `shard.count()` parts, by skipping the
+    /// subtrees of derangements with a common start that come before the part.
+    ///
+    /// If the number of derangements doesn't fit in a `u128`, the first shard gets all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `shard`: the part of the derangements to keep
+    ///
+    /// returns: the iterator, restricted to the shard
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use itertools::assert_equal;
+    /// use derangements::{derangements, Shard};
+    /// assert_equal(derangements(0usize..4, 4).shard(Shard::new(1, 3)), [[2, 0, 3, 1], [2, 3, 0, 1], [2, 3, 1, 0]]);
+    /// ```
+    pub fn shard(mut self, shard: Shard) -> Self {
+        match self.remaining {
+            Some(remaining) => {
+                let (start, end) = shard.bounds(remaining);
+                if start < end {
+                    // None of the counts can overflow, as they are all bounded by the total
+                    let (fixed, k) = (&self.fixed, self.matchings.restriction().positions());
+                    self.matchings
+                        .skip_ahead(start, |prefix| completions(fixed, k, prefix).unwrap());
+                }
+                self.remaining = Some(end - start);
+            }
+            None if shard.index() > 0 => self.remaining = Some(0),
+            None => {}
+        }
+        self
     }
 }
 
 impl<I> Iterator for Derangements<I>
 where
     I: Iterator,
-    I::Item: Clone + TryInto<usize> + Ord + Copy + Debug,
+    I::Item: Clone,
 {
     type Item = Vec<I::Item>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.permutations.next() {
-            None => return None,
-            Some(x) => {
-                if !x
-                    .iter()
-                    .enumerate()
-                    .any(|x| x.0 == (*x.1).try_into().unwrap_or(usize::MAX))
-                {
-                    return Some(x);
-                }
-            }
-        };
-        self.next()
+        if self.remaining == Some(0) {
+            return None;
+        }
+        // Values are never placed at an index that equals them, so every completed selection is a
+        // derangement and nothing has to be filtered afterwards
+        let indices = self.matchings.advance()?;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(indices.iter().map(|&i| self.values[i].clone()).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl<I> Derangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
     }
 }
 
+/// `len` panics once the number of derangements left exceeds `usize::MAX`, which can happen from
+/// 21 values on 64-bit targets; `remaining` still counts them then.
+impl<I> ExactSizeIterator for Derangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+}
+
 impl<I> FusedIterator for Derangements<I>
 where
     I: Iterator,
-    I::Item: Clone + TryInto<usize> + Ord + Copy + Debug,
+    I::Item: Clone,
 {
 }
 
-#[derive(Debug, Clone)]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "DistinctDerangementsState<I>"
+    )
+)]
 pub struct DistinctDerangements<I: Iterator> {
-    buffer: Vec<I::Item>,
-    start: bool,
-    index: usize,
+    permutations: DistinctPermutations<I>,
+    remaining: Option<u128>,
+}
+
+impl<I> Clone for DistinctDerangements<I>
+where
+    I: Clone + Iterator,
+    I::Item: Clone,
+{
+    clone_fields!(permutations, remaining);
+}
+
+impl<I> Debug for DistinctDerangements<I>
+where
+    I: Iterator + Debug,
+    I::Item: Debug,
+{
+    debug_fmt_fields!(DistinctDerangements, permutations, remaining);
+}
+
+/// The fields of a deserialized `DistinctDerangements`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct DistinctDerangementsState<I: Iterator> {
+    permutations: DistinctPermutations<I>,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<DistinctDerangementsState<I>> for DistinctDerangements<I> {
+    type Error = &'static str;
+
+    fn try_from(state: DistinctDerangementsState<I>) -> Result<Self, Self::Error> {
+        // The derangements that are left are among the permutations that are left
+        if let (Some(remaining), Some(permutations)) =
+            (state.remaining, state.permutations.remaining())
+        {
+            if remaining > permutations {
+                return Err("more derangements remaining than permutations");
+            }
+        }
+        Ok(DistinctDerangements {
+            permutations: state.permutations,
+            remaining: state.remaining,
+        })
+    }
 }
 
 /// Derange k or all elements of an iterable without repetitions.
@@ -147,83 +324,177 @@ where
     I::Item: Ord + Clone,
     usize: From<I::Item>,
 {
-    let mut buffer = Vec::from_iter(iter);
-    buffer.sort_unstable_by(|a, b| b.cmp(a));
-    let length = buffer.len();
+    let permutations = distinct_permutations(iter);
+    let remaining = count_distinct_derangements(permutations.values().iter().cloned());
     DistinctDerangements {
-        buffer,
-        start: true,
-        index: length.saturating_sub(2),
+        permutations,
+        remaining,
     }
 }
 
-impl<I> Iterator for DistinctDerangements<I>
+impl<I> DistinctDerangements<I>
 where
     I: Iterator,
     I::Item: Ord + Copy + Clone,
     usize: From<I::Item>,
 {
-    type Item = Vec<I::Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Start iteration with buffer itself
-        if self.start {
-            self.start = false;
-            if !self
-                .buffer
-                .iter()
-                .enumerate()
-                .any(|x| x.0 == <I::Item as Into<usize>>::into(*x.1))
-            {
-                return Some(self.buffer.clone());
+    /// Restrict the derangements that are left to one of `shard.count()` parts, by skipping the
+    /// derangements that come before the part.
+    ///
+    /// The distinct permutations are not generated in an order that groups them by their first
+    /// elements, but they do come in runs through all orders of a prefix with the rest unchanged.
+    /// Whole runs are skipped by counting their derangements, and only the permutations that
+    /// start runs which don't fit in the skipped part are generated one by one.
+    ///
+    /// If the number of derangements doesn't fit in a `u128`, the first shard gets all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `shard`: the part of the derangements to keep
+    ///
+    /// returns: the iterator, restricted to the shard
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use itertools::assert_equal;
+    /// use derangements::{distinct_derangements, Shard};
+    /// assert_equal(distinct_derangements(vec![0usize, 0, 1, 2, 2].into_iter()).shard(Shard::new(1, 2)),
+    ///     [[1, 2, 0, 2, 0], [2, 0, 1, 0, 2], [2, 0, 0, 1, 2], [1, 2, 0, 0, 2], [1, 0, 0, 2, 2]]);
+    /// ```
+    pub fn shard(mut self, shard: Shard) -> Self {
+        match self.remaining {
+            Some(remaining) => {
+                let (start, end) = shard.bounds(remaining);
+                self.skip_derangements(start);
+                self.remaining = Some(end - start);
             }
+            None if shard.index() > 0 => self.remaining = Some(0),
+            None => {}
         }
+        self
+    }
+}
 
-        // Exhausted iteration
-        let has_two_next = self.index + 2 < self.buffer.len();
-        if !has_two_next
-            && (self.buffer.len() <= self.index + 1
-                || self.buffer[0] <= self.buffer[self.index + 1])
-        {
-            return None;
+impl<I> DistinctDerangements<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+    usize: From<I::Item>,
+{
+    /// Skip the next `skip` derangements, jumping over the longest runs of permutations that
+    /// don't have more derangements than are left to skip, and return the number of permutations
+    /// that were generated.
+    fn skip_derangements(&mut self, count: u128) -> usize {
+        let mut generated = 0;
+        let mut skip = count;
+        while skip > 0 {
+            let Some(permutation) = self.permutations.next() else {
+                break;
+            };
+            generated += 1;
+            let run = self.permutations.runs().find_map(|p| {
+                let count = run_derangements(&permutation, p)?;
+                (count <= skip).then_some((p, count))
+            });
+            match run {
+                Some((p, count)) => {
+                    self.permutations.skip_run(p);
+                    skip -= count;
+                }
+                None if is_derangement(&permutation) => skip -= 1,
+                None => {}
+            }
         }
-
-        // Determine shift index
-        let shift_index = if has_two_next && self.buffer[self.index + 2] <= self.buffer[self.index]
-        {
-            self.index + 2
-        } else {
-            self.index + 1
-        };
-
-        // Prefix shift
-        let shift_elem = self.buffer[shift_index];
-        let mut swap_index = shift_index;
-        while swap_index > 0 {
-            self.buffer[swap_index] = self.buffer[swap_index - 1];
-            swap_index -= 1;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= count - skip;
         }
-        self.buffer[0] = shift_elem;
+        generated
+    }
+}
+
+fn is_derangement<T: Copy>(x: &[T]) -> bool
+where
+    usize: From<T>,
+{
+    x.iter()
+        .enumerate()
+        .all(|(index, &value)| usize::from(value) != index)
+}
 
-        // Update index
-        if self.buffer[0] < self.buffer[1] {
-            self.index = 0;
-        } else {
-            self.index += 1;
+/// Number of derangements among the distinct permutations of the first p values of x, with the
+/// other values where they are in x
+fn run_derangements<T: Copy>(x: &[T], p: usize) -> Option<u128>
+where
+    usize: From<T>,
+{
+    if x.iter()
+        .enumerate()
+        .skip(p)
+        .any(|(i, &v)| usize::from(v) == i)
+    {
+        return Some(0);
+    }
+    let multiplicities = x[..p]
+        .iter()
+        .map(|&value| usize::from(value))
+        .sorted()
+        .dedup_with_count()
+        .map(|(m, value)| (value, m))
+        .collect_vec();
+    counting::distinct_derangements(0..p, &multiplicities)
+}
+
+impl<I> Iterator for DistinctDerangements<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+    usize: From<I::Item>,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
         }
-        if !self
-            .buffer
-            .iter()
-            .enumerate()
-            .any(|x| x.0 == <I::Item as Into<usize>>::into(*x.1))
-        {
-            Some(self.buffer.clone())
-        } else {
-            self.next()
+        let next = self.permutations.find(|x| is_derangement(x));
+        if next.is_some() {
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= 1;
+            }
         }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl<I: Iterator> DistinctDerangements<I> {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
     }
 }
 
+/// As for `Derangements`, `len` panics if the number of derangements left does not fit in a `usize`.
+impl<I> ExactSizeIterator for DistinctDerangements<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+    usize: From<I::Item>,
+{
+}
+
+impl<I> FusedIterator for DistinctDerangements<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+    usize: From<I::Item>,
+{
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -245,11 +516,11 @@ mod tests {
         assert_equal(derangements(vec![0usize, 2].into_iter(), 2), [[2, 0]]);
         assert_equal(
             derangements(vec![0u8, 1, 3].into_iter(), 3),
-            [[3, 0, 1], [1, 0, 3], [1, 3, 0]],
+            [[1, 0, 3], [1, 3, 0], [3, 0, 1]],
         );
         assert_equal(
             derangements(vec![0usize, 1, 3].into_iter(), 2),
-            [[3, 0], [1, 0], [1, 3]],
+            [[1, 0], [1, 3], [3, 0]],
         );
         assert_equal(
             derangements(vec![0u16, 1, 1].into_iter(), 3),
@@ -261,6 +532,168 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nonrange_filtered() {
+        for n in 0..8usize {
+            for k in 0..=n {
+                assert_equal(
+                    derangements(0..n, k),
+                    Itertools::permutations(0..n, k)
+                        .filter(|x| x.iter().enumerate().all(|(i, &v)| i != v))
+                        .sorted(),
+                );
+            }
+        }
+        assert_equal(
+            derangements(vec![1u8, 1, 2, 5].into_iter(), 4).sorted(),
+            Itertools::permutations(vec![1u8, 1, 2, 5].into_iter(), 4)
+                .filter(|x| x.iter().enumerate().all(|(i, &v)| i != v as usize))
+                .sorted(),
+        );
+    }
+
+    #[test]
+    fn test_size_hint() {
+        for values in [
+            vec![0usize, 1, 2, 3, 4],
+            vec![1, 1, 2, 0, 0],
+            vec![0, 0, 0, 7, 9],
+        ] {
+            for k in 0..=values.len() + 1 {
+                let mut iter = derangements(values.clone().into_iter(), k);
+                let mut count = iter.clone().count();
+                loop {
+                    assert_eq!(iter.remaining(), Some(count as u128));
+                    assert_eq!(iter.len(), count);
+                    if iter.next().is_none() {
+                        break;
+                    }
+                    count -= 1;
+                }
+            }
+            let mut iter = distinct_derangements(values.clone().into_iter());
+            let mut count = iter.clone().count();
+            loop {
+                assert_eq!(iter.remaining(), Some(count as u128));
+                assert_eq!(iter.len(), count);
+                if iter.next().is_none() {
+                    break;
+                }
+                count -= 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_remaining_at_the_limit() {
+        // !34 is the largest subfactorial that fits, and its shards are split evenly
+        let total = crate::count_derangements(34);
+        assert!(total.is_some());
+        let iter = derangements(0..34usize, 34);
+        assert_eq!(iter.remaining(), total);
+        let shards = (0..3)
+            .map(|index| iter.clone().shard(Shard::new(index, 3)))
+            .collect_vec();
+        let sizes = shards.iter().map(|x| x.remaining().unwrap()).collect_vec();
+        assert_eq!(sizes.iter().sum::<u128>(), total.unwrap());
+        assert!(sizes
+            .iter()
+            .minmax()
+            .into_option()
+            .is_some_and(|(a, b)| b - a <= 1));
+        let first = shards[2].clone().next().unwrap();
+        assert!(first.iter().enumerate().all(|(i, &v)| i != v));
+        assert_ne!(Some(first), iter.clone().next());
+    }
+
+    #[test]
+    fn test_shard() {
+        for values in [
+            vec![0usize, 1, 2, 3, 4, 5, 6],
+            vec![1, 1, 2, 0, 0, 3],
+            vec![0, 0, 0, 7, 9],
+        ] {
+            for count in [1, 2, 3, 7, 50] {
+                for skip in [0, 5] {
+                    for k in [values.len() / 2, values.len()] {
+                        let mut iter = derangements(values.clone().into_iter(), k);
+                        iter.nth(skip);
+                        let shards = (0..count)
+                            .map(|index| iter.clone().shard(Shard::new(index, count)))
+                            .collect_vec();
+                        let (min, max) = shards
+                            .iter()
+                            .map(|x| x.remaining().unwrap())
+                            .minmax()
+                            .into_option()
+                            .unwrap();
+                        assert!(max - min <= 1);
+                        assert_equal(shards.into_iter().flatten(), iter);
+                    }
+
+                    let mut iter = distinct_derangements(values.clone().into_iter());
+                    iter.nth(skip);
+                    let shards = (0..count)
+                        .map(|index| iter.clone().shard(Shard::new(index, count)))
+                        .collect_vec();
+                    let (min, max) = shards
+                        .iter()
+                        .map(|x| x.remaining().unwrap())
+                        .minmax()
+                        .into_option()
+                        .unwrap();
+                    assert!(max - min <= 1);
+                    assert_equal(shards.into_iter().flatten(), iter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_distinct_skip() {
+        for values in [
+            vec![0usize, 1, 2, 3, 4, 5],
+            vec![1, 1, 2, 0, 0, 3, 3],
+            vec![0, 0, 0, 7, 9, 2],
+            vec![5, 4, 4, 1, 0, 2, 2],
+        ] {
+            let iter = distinct_derangements(values.into_iter());
+            let total = iter.remaining().unwrap();
+            for count in 0..=total {
+                let mut skipped = iter.clone();
+                skipped.skip_derangements(count);
+                assert_eq!(skipped.remaining(), Some(total - count));
+                assert_equal(skipped, iter.clone().dropping(count as usize));
+            }
+        }
+
+        // The last of ten shards is reached without generating nearly all derangements before it
+        let iter = distinct_derangements(vec![0usize, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into_iter());
+        let total = iter.remaining().unwrap();
+        assert!(total > 1_000_000);
+        let (start, _) = Shard::new(9, 10).bounds(total);
+        let mut skipped = iter.clone();
+        assert!(skipped.skip_derangements(start) < 200);
+        assert_equal(skipped.take(100), iter.dropping(start as usize).take(100));
+    }
+
+    #[test]
+    fn test_distinct_filtered() {
+        for values in [
+            vec![0usize, 1, 2, 3, 4],
+            vec![1, 1, 2, 0, 0],
+            vec![0, 0, 0, 7, 9, 9],
+        ] {
+            assert_equal(
+                distinct_derangements(values.clone().into_iter()).sorted(),
+                Itertools::permutations(values.clone().into_iter(), values.len())
+                    .filter(|x| x.iter().enumerate().all(|(i, &v)| i != v))
+                    .sorted()
+                    .dedup(),
+            );
+        }
+    }
+
     #[test]
     fn test_nonrange_distinct() {
         assert_equal(
@@ -273,6 +706,60 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = derangements(0..7usize, 5);
+        iter.nth(100);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: Derangements<std::ops::Range<usize>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+
+        let mut iter = distinct_derangements(vec![0usize, 0, 1, 2, 2, 3].into_iter());
+        iter.nth(5);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: DistinctDerangements<std::vec::IntoIter<usize>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        type Deranged = Derangements<std::ops::Range<usize>>;
+        let mut iter = derangements(0..7usize, 5);
+        iter.nth(100);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(serde_json::from_value::<Deranged>(state.clone()).is_ok());
+
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 2000.into();
+        assert!(serde_json::from_value::<Deranged>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["values"].as_array_mut().unwrap().pop();
+        assert!(serde_json::from_value::<Deranged>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["matchings"]["search"]["stack"][1] = 8.into();
+        assert!(serde_json::from_value::<Deranged>(corrupted).is_err());
+        let mut corrupted = state;
+        corrupted["matchings"]["restriction"]["allowed"][0] = u64::MAX.into();
+        assert!(serde_json::from_value::<Deranged>(corrupted).is_err());
+
+        let mut iter = distinct_derangements(vec![0usize, 0, 1, 2, 2, 3].into_iter());
+        iter.nth(5);
+        let mut corrupted = serde_json::to_value(&iter).unwrap();
+        corrupted["remaining"] = 1000.into();
+        assert!(
+            serde_json::from_value::<DistinctDerangements<std::vec::IntoIter<usize>>>(corrupted)
+                .is_err()
+        );
+
+        let shard = serde_json::json!({"index": 3, "count": 3});
+        assert!(serde_json::from_value::<Shard>(shard).is_err());
+    }
+
     // // Test to uncomment when I want to time performance - to convert into proper benchmark
     // #[test]
     // fn test_time() {
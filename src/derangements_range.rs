@@ -1,6 +1,12 @@
+use crate::counting;
+use crate::shard::Shard;
 use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "DerangementsRangeState"))]
 pub struct DerangementsRangeIterator {
     lag1_done: bool,
     lag: Option<Box<DerangementsRangeIterator>>,
@@ -8,10 +14,95 @@ pub struct DerangementsRangeIterator {
     n: usize,
     curr_lag: Vec<usize>,
     count: usize,
+    odd: Option<bool>,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `DerangementsRangeIterator`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct DerangementsRangeState {
+    lag1_done: bool,
+    lag: Option<Box<DerangementsRangeIterator>>,
+    init: bool,
+    n: usize,
+    curr_lag: Vec<usize>,
+    count: usize,
+    odd: Option<bool>,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<DerangementsRangeState> for DerangementsRangeIterator {
+    type Error = &'static str;
+
+    fn try_from(state: DerangementsRangeState) -> Result<Self, Self::Error> {
+        let n = state.n;
+        let iter = DerangementsRangeIterator {
+            lag1_done: state.lag1_done,
+            lag: state.lag,
+            init: state.init,
+            n,
+            curr_lag: state.curr_lag,
+            count: state.count,
+            odd: state.odd,
+            remaining: state.remaining,
+        };
+        let fresh = derangements_by_range_with_parity(n, iter.odd);
+        let left = if n < 2 || iter.init {
+            // Nothing has been generated from a lag yet
+            if iter.lag.is_some() || iter.count != fresh.count || n >= 2 && iter.lag1_done {
+                return Err("lag of an iterator that hasn't started");
+            }
+            fresh.remaining
+        } else {
+            let Some(lag) = iter.lag.as_ref() else {
+                return Err("missing lag of an iterator that has started");
+            };
+            let m = if iter.lag1_done { n - 2 } else { n - 1 };
+            if lag.n != m || lag.odd != iter.odd.map(|odd| !odd) || iter.count > n - 1 {
+                return Err("lag of the wrong size or parity");
+            }
+
+            // Part 1 places n - 1 into a derangement of 0..n-1, and part 2 has made the values
+            // above n - 1 - count one larger
+            if iter.count < n - 1 {
+                let skipped = match iter.lag1_done {
+                    true => n - 1 - iter.count.max(1),
+                    false => m,
+                };
+                let expected = (0..=m).filter(|&x| x != skipped);
+                if iter.curr_lag.len() != m || iter.curr_lag.iter().copied().sorted().ne(expected) {
+                    return Err("current lagged derangement doesn't match the count");
+                }
+            }
+
+            // The rest of this block, a block per lagged derangement, and the second part
+            let block = (n - 1) as u128;
+            let second = match iter.lag1_done {
+                true => Some(0),
+                false => iter.lag_derangements(n - 2).remaining,
+            };
+            lag.remaining
+                .zip(second)
+                .and_then(|(lag, second)| lag.checked_add(second)?.checked_mul(block))
+                .and_then(|x| x.checked_add(block - iter.count as u128))
+        };
+        if let (Some(remaining), Some(left)) = (iter.remaining, left) {
+            if remaining > left {
+                return Err("more derangements remaining than are left");
+            }
+        }
+        Ok(iter)
+    }
 }
 
 /// Derange all elements of a range of 0 to n (non-inclusive).
 ///
+/// Skipping ahead with `nth` doesn't generate the skipped derangements, so it can be used to
+/// resume at an arbitrary offset.
+///
 /// # Arguments
 ///
 /// * `n`: usize integer that determines the range to derange
@@ -24,8 +115,66 @@ pub struct DerangementsRangeIterator {
 /// use itertools::{assert_equal, Itertools};
 /// use derangements::derangements_by_range;
 /// assert_equal(derangements_by_range(3), [[2, 0, 1], [1, 2, 0]]);
+///
+/// // Jump ahead to the derangement at position 10^15
+/// assert_eq!(derangements_by_range(20).nth(1_000_000_000_000_000).unwrap().len(), 20);
 /// ```
 pub fn derangements_by_range(n: usize) -> DerangementsRangeIterator {
+    derangements_by_range_with_parity(n, None)
+}
+
+/// Derange all elements of a range of 0 to n (non-inclusive) with an even number of
+/// transpositions.
+///
+/// Every derangement is made from a lagged derangement of n - 1 or n - 2 elements with a single
+/// transposition, so it has the opposite parity. The derangements of `derangements_by_range` that
+/// are even are therefore built from the odd lagged derangements only, recursively, without
+/// computing the sign of any derangement. They come in the same order, and skipping ahead with
+/// `nth` or `shard` works as for `derangements_by_range`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: iterable with the even derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::even_derangements_by_range;
+/// assert_equal(even_derangements_by_range(3), [[2, 0, 1], [1, 2, 0]]);
+/// assert_equal(even_derangements_by_range(4), [[1, 0, 3, 2], [2, 3, 0, 1], [3, 2, 1, 0]]);
+/// ```
+pub fn even_derangements_by_range(n: usize) -> DerangementsRangeIterator {
+    derangements_by_range_with_parity(n, Some(false))
+}
+
+/// Derange all elements of a range of 0 to n (non-inclusive) with an odd number of transpositions.
+///
+/// These are built from the even lagged derangements only, as for `even_derangements_by_range`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: iterable with the odd derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::odd_derangements_by_range;
+/// assert_equal(odd_derangements_by_range(2), [[1, 0]]);
+/// assert_eq!(odd_derangements_by_range(3).count(), 0);
+/// assert_eq!(odd_derangements_by_range(4).count(), 6);
+/// ```
+pub fn odd_derangements_by_range(n: usize) -> DerangementsRangeIterator {
+    derangements_by_range_with_parity(n, Some(true))
+}
+
+/// The derangements of 0..n, or only the odd (or even) ones if `odd` is set
+fn derangements_by_range_with_parity(n: usize, odd: Option<bool>) -> DerangementsRangeIterator {
     DerangementsRangeIterator {
         lag1_done: false,
         lag: None,
@@ -33,6 +182,11 @@ pub fn derangements_by_range(n: usize) -> DerangementsRangeIterator {
         n,
         curr_lag: vec![],
         count: n.saturating_sub(1),
+        odd,
+        remaining: match odd {
+            Some(odd) => counting::parity_derangements(n, odd),
+            None => counting::subfactorial(n),
+        },
     }
 }
 
@@ -40,9 +194,169 @@ impl Iterator for DerangementsRangeIterator {
     type Item = Vec<usize>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        let next = self.generate();
+        if next.is_some() {
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= 1;
+            }
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self.remaining {
+            Some(remaining) if n as u128 >= remaining => {
+                self.remaining = Some(0);
+                None
+            }
+            _ => {
+                self.skip_ahead(n as u128);
+                self.next()
+            }
+        }
+    }
+}
+
+/// The length is exact, but `len` panics if it does not fit in a `usize`: on 64-bit targets, that
+/// is only guaranteed up to n = 20. `remaining` gives the length as a `u128` instead.
+impl ExactSizeIterator for DerangementsRangeIterator {}
+
+impl DerangementsRangeIterator {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`.
+    ///
+    /// This is also reported by `size_hint` when it fits in a `usize`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use derangements::derangements_by_range;
+    /// let mut iter = derangements_by_range(4);
+    /// iter.next();
+    /// assert_eq!(iter.remaining(), Some(8));
+    /// assert_eq!(derangements_by_range(21).remaining(), Some(18795307255050944540));
+    /// assert_eq!(derangements_by_range(40).remaining(), None);
+    /// ```
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+
+    /// Restrict the derangements that are left to one of `shard.count()` parts, by skipping ahead
+    /// to the start of the part.
+    ///
+    /// If the number of derangements doesn't fit in a `u128`, the first shard gets all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `shard`: the part of the derangements to keep
+    ///
+    /// returns: the iterator, restricted to the shard
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use itertools::assert_equal;
+    /// use derangements::{derangements_by_range, Shard};
+    /// assert_equal(derangements_by_range(4).shard(Shard::new(1, 3)), [[3, 2, 0, 1], [1, 3, 0, 2], [1, 2, 3, 0]]);
+    /// ```
+    pub fn shard(mut self, shard: Shard) -> Self {
+        match self.remaining {
+            Some(remaining) => {
+                let (start, end) = shard.bounds(remaining);
+                if start < end {
+                    self.skip_ahead(start);
+                }
+                self.remaining = Some(end - start);
+            }
+            None if shard.index() > 0 => self.remaining = Some(0),
+            None => {}
+        }
+        self
+    }
+
+    /// The lagged derangements of m elements, which need the opposite parity of these ones
+    fn lag_derangements(&self, m: usize) -> DerangementsRangeIterator {
+        derangements_by_range_with_parity(m, self.odd.map(|odd| !odd))
+    }
+
+    /// The length n of the range that is deranged
+    #[cfg(feature = "serde")]
+    pub(crate) fn n(&self) -> usize {
+        self.n
+    }
+
+    /// Skip k derangements, where k has to be less than the number of remaining derangements.
+    ///
+    /// Each lagged derangement results in a block of n - 1 derangements, so whole blocks are
+    /// skipped by skipping lagged derangements instead, recursively.
+    pub(crate) fn skip_ahead(&mut self, k: u128) {
+        if k == 0 || self.n < 2 {
+            return;
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= k;
+        }
+        if self.init {
+            self.init = false;
+            self.lag = Option::from(Box::from(self.lag_derangements(self.n - 1)))
+        }
+
+        // Stay within the current block if possible
+        let block = (self.n - 1) as u128;
+        let left = block - self.count as u128;
+        if k < left {
+            self.skip_in_block(k as usize);
+            return;
+        }
+
+        // Otherwise skip to the block that contains the target, switching to the lag of n-2 if
+        // the lag of n-1 runs out
+        let k = k - left;
+        let mut blocks = k / block;
+        let lag = self.lag.as_mut().unwrap();
+        if !self.lag1_done && lag.remaining.is_some_and(|x| blocks >= x) {
+            blocks -= lag.remaining.unwrap();
+            self.lag1_done = true;
+            self.lag = Option::from(Box::from(self.lag_derangements(self.n - 2)));
+        }
+        let lag = self.lag.as_mut().unwrap();
+        lag.skip_ahead(blocks);
+        self.curr_lag = lag.next().unwrap();
+        self.count = 0;
+        self.skip_in_block((k % block) as usize);
+    }
+
+    /// Skip k derangements of the current block, applying the changes to `curr_lag` that the
+    /// skipped steps would have made in part 2.
+    fn skip_in_block(&mut self, k: usize) {
+        if self.lag1_done && k > 0 {
+            // Steps with count c > 0 increment the value n - 2 - c
+            let first = self.count.max(1);
+            let last = self.count + k - 1;
+            if last >= first {
+                let (low, high) = (self.n - 2 - last, self.n - 2 - first);
+                for x in self
+                    .curr_lag
+                    .iter_mut()
+                    .filter(|x| (low..=high).contains(*x))
+                {
+                    *x += 1;
+                }
+            }
+        }
+        self.count += k;
+    }
+
+    fn generate(&mut self) -> Option<Vec<usize>> {
         match self.n {
             0 => {
-                return if self.lag1_done {
+                return if self.lag1_done || self.odd == Some(true) {
                     None
                 } else {
                     self.lag1_done = true;
@@ -56,7 +370,7 @@ impl Iterator for DerangementsRangeIterator {
         // First iteration: init derangements for 1 lag lower
         if self.init {
             self.init = false;
-            self.lag = Option::from(Box::from(derangements_by_range(self.n - 1)))
+            self.lag = Option::from(Box::from(self.lag_derangements(self.n - 1)))
         }
 
         // For each draw of lag derangements: get a new one after n-1 iterations
@@ -71,7 +385,7 @@ impl Iterator for DerangementsRangeIterator {
                         return None;
                     };
                     self.lag1_done = true;
-                    self.lag = Option::from(Box::from(derangements_by_range(self.n - 2)));
+                    self.lag = Option::from(Box::from(self.lag_derangements(self.n - 2)));
                     let next2 = self.lag.as_mut().unwrap().next();
                     match next2 {
                         None => return None,
@@ -135,6 +449,156 @@ mod tests {
         assert_eq!(derangements_by_range(8).collect_vec().len(), 14833);
     }
 
+    #[test]
+    fn test_nth() {
+        for n in 0..9 {
+            let all = derangements_by_range(n).collect_vec();
+            for step in [0, 1, 2, 3, 7, 40, 1000] {
+                let mut iter = derangements_by_range(n);
+                let mut skipped = all.iter().skip(step).step_by(step + 1);
+                loop {
+                    let next = iter.nth(step);
+                    assert_eq!(next.as_ref(), skipped.next());
+                    if next.is_none() {
+                        break;
+                    }
+                }
+                assert_eq!(iter.next(), None);
+            }
+            // Interleave single steps with jumps of various sizes
+            let mut iter = derangements_by_range(n);
+            let mut position = 0;
+            for jump in (0..).map(|x| (x * x) % 97) {
+                position += jump;
+                assert_eq!(iter.nth(jump).as_ref(), all.get(position));
+                assert_eq!(
+                    iter.remaining(),
+                    Some(all.len().saturating_sub(position + 1) as u128)
+                );
+                position += 1;
+                if position >= all.len() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_nth_large() {
+        // Jumping far into a large range should be fast and agree with unranking
+        let mut iter = derangements_by_range(30);
+        let target = 123_456_789_012_345_678;
+        assert_eq!(
+            iter.nth(target),
+            Some(crate::derangement_unrank_by_range(30, target as u128))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(crate::derangement_unrank_by_range(30, target as u128 + 1))
+        );
+    }
+
+    #[test]
+    fn test_shard() {
+        for n in 0..=8 {
+            for count in [1, 2, 3, 7, 50] {
+                for skip in [0, 5] {
+                    let mut iter = derangements_by_range(n);
+                    iter.nth(skip);
+                    let shards = (0..count)
+                        .map(|index| iter.clone().shard(Shard::new(index, count)))
+                        .collect_vec();
+                    let (min, max) = shards
+                        .iter()
+                        .map(|x| x.remaining().unwrap())
+                        .minmax()
+                        .into_option()
+                        .unwrap();
+                    assert!(max - min <= 1);
+                    assert_equal(shards.into_iter().flatten(), iter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_hint() {
+        for n in 0..8 {
+            let mut iter = derangements_by_range(n);
+            let mut count = iter.clone().count();
+            loop {
+                assert_eq!(iter.remaining(), Some(count as u128));
+                assert_eq!(iter.len(), count);
+                if iter.next().is_none() {
+                    break;
+                }
+                count -= 1;
+            }
+        }
+        assert_eq!(derangements_by_range(20).len(), 895014631192902121);
+        assert_eq!(derangements_by_range(40).size_hint(), (usize::MAX, None));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic]
+    fn test_len_too_large() {
+        // !21 fits in a u128 but not in a u64
+        derangements_by_range(21).len();
+    }
+
+    fn is_odd(x: &[usize]) -> bool {
+        let inversions = (0..x.len())
+            .tuple_combinations()
+            .filter(|&(i, j)| x[i] > x[j])
+            .count();
+        inversions % 2 == 1
+    }
+
+    #[test]
+    fn test_parity() {
+        for n in 0..=9 {
+            for (odd, iter) in [
+                (false, even_derangements_by_range(n)),
+                (true, odd_derangements_by_range(n)),
+            ] {
+                let expected = derangements_by_range(n)
+                    .filter(|x| is_odd(x) == odd)
+                    .collect_vec();
+                assert_eq!(iter.remaining(), Some(expected.len() as u128));
+                assert_eq!(
+                    counting::parity_derangements(n, odd),
+                    Some(expected.len() as u128)
+                );
+                assert_equal(iter, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parity_nth_and_shard() {
+        for n in 0..=8 {
+            let all = odd_derangements_by_range(n).collect_vec();
+            for step in [0, 1, 4, 30] {
+                let mut iter = odd_derangements_by_range(n);
+                assert_equal(
+                    (0..).map_while(|_| iter.nth(step)),
+                    all.iter().skip(step).step_by(step + 1).cloned(),
+                );
+            }
+            let shards =
+                (0..3).flat_map(|index| even_derangements_by_range(n).shard(Shard::new(index, 3)));
+            assert_equal(shards, even_derangements_by_range(n));
+        }
+
+        // Jumping far ahead only visits lagged derangements of the right parity
+        let mut iter = even_derangements_by_range(25);
+        let target = 1_000_000_000_000_000;
+        let next = even_derangements_by_range(25).nth(target + 1);
+        assert!(!is_odd(&iter.nth(target).unwrap()));
+        assert_eq!(iter.next(), next);
+    }
+
     #[test]
     fn test_nonrange_range() {
         for k in 0..8 {
@@ -144,4 +608,68 @@ mod tests {
             );
         }
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        // Interrupt in both parts, so that the nested lag iterators differ in state
+        for skip in [0, 10, 700, 1500, 1852] {
+            let mut iter = derangements_by_range(7);
+            iter.nth(skip);
+            let json = serde_json::to_string(&iter).unwrap();
+            let resumed: DerangementsRangeIterator = serde_json::from_str(&json).unwrap();
+            assert_eq!(resumed.remaining(), iter.remaining());
+            assert_equal(resumed, iter);
+        }
+
+        let mut iter = odd_derangements_by_range(7);
+        iter.nth(400);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: DerangementsRangeIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_every_state() {
+        for mut iter in [
+            derangements_by_range(6),
+            even_derangements_by_range(6),
+            odd_derangements_by_range(5),
+            derangements_by_range(7).shard(Shard::new(1, 3)),
+        ] {
+            loop {
+                let json = serde_json::to_string(&iter).unwrap();
+                let mut resumed: DerangementsRangeIterator = serde_json::from_str(&json).unwrap();
+                let next = iter.next();
+                assert_eq!(resumed.next(), next);
+                if next.is_none() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let mut iter = derangements_by_range(6);
+        iter.nth(100);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(serde_json::from_value::<DerangementsRangeIterator>(state.clone()).is_ok());
+
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 200.into();
+        assert!(serde_json::from_value::<DerangementsRangeIterator>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["curr_lag"][0] = 9.into();
+        assert!(serde_json::from_value::<DerangementsRangeIterator>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["lag"]["n"] = 3.into();
+        assert!(serde_json::from_value::<DerangementsRangeIterator>(corrupted).is_err());
+        let mut corrupted = state;
+        corrupted["lag"] = serde_json::Value::Null;
+        assert!(serde_json::from_value::<DerangementsRangeIterator>(corrupted).is_err());
+    }
 }
@@ -0,0 +1,421 @@
+use crate::counting;
+use crate::restricted_permutations::RestrictedPermutations;
+use crate::restriction::{perfect_matchings, PerfectMatchings, Restriction};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::iter::FusedIterator;
+use std::sync::OnceLock;
+
+/// Whether d is the largest or the smallest displacement of an element
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Bound {
+    Max,
+    Min,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "DisplacementRangeState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct DisplacementRangeIterator {
+    matchings: PerfectMatchings,
+    bound: Bound,
+    n: usize,
+    d: usize,
+    generated: u128,
+    /// The total number of derangements, which is only counted once it is asked for
+    #[cfg_attr(feature = "serde", serde(skip))]
+    total: OnceLock<Option<u128>>,
+}
+
+/// The fields of a deserialized `DisplacementRangeIterator`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct DisplacementRangeState {
+    matchings: PerfectMatchings,
+    bound: Bound,
+    n: usize,
+    d: usize,
+    generated: u128,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<DisplacementRangeState> for DisplacementRangeIterator {
+    type Error = &'static str;
+
+    fn try_from(state: DisplacementRangeState) -> Result<Self, Self::Error> {
+        let (n, d) = (state.n, state.d);
+        let targets = (0..n).collect::<Vec<_>>();
+        let (restriction, total) = match state.bound {
+            Bound::Max => (
+                displacement_restriction(&targets, n, 1, d),
+                counting::max_displacement_derangements::<u128>(n, d),
+            ),
+            Bound::Min => (
+                displacement_restriction(&targets, n, d.max(1), usize::MAX),
+                counting::min_displacement_derangements::<u128>(n, d),
+            ),
+        };
+        if *state.matchings.restriction() != restriction {
+            return Err("search doesn't match the displacement");
+        }
+        if total.is_some_and(|total| state.generated > total) {
+            return Err("more generated derangements than there are in total");
+        }
+        Ok(DisplacementRangeIterator {
+            matchings: state.matchings,
+            bound: state.bound,
+            n,
+            d,
+            generated: state.generated,
+            total: OnceLock::from(total),
+        })
+    }
+}
+
+/// The restriction where the value with index i can only be at the positions that are between
+/// `min` and `max` (inclusive) away from `targets[i]`.
+fn displacement_restriction(targets: &[usize], k: usize, min: usize, max: usize) -> Restriction {
+    Restriction::from_fn(targets, k, |position, &x| {
+        (min..=max).contains(&position.abs_diff(x))
+    })
+}
+
+/// Derange the range of 0 to n (non-inclusive) while moving each element at most d positions.
+///
+/// These are the derangements of `derangements_with_max_displacement` for the range, in the same
+/// lexicographic order: they are built directly by only placing elements within their band of
+/// positions.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `d`: usize integer that determines the largest displacement of an element
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::derangements_with_max_displacement_by_range;
+/// assert_equal(derangements_with_max_displacement_by_range(4, 1), [[1, 0, 3, 2]]);
+/// assert_equal(derangements_with_max_displacement_by_range(4, 2),
+///     [[1, 0, 3, 2], [1, 3, 0, 2], [2, 0, 3, 1], [2, 3, 0, 1]]);
+/// ```
+pub fn derangements_with_max_displacement_by_range(
+    n: usize,
+    d: usize,
+) -> DisplacementRangeIterator {
+    let targets = (0..n).collect::<Vec<_>>();
+    DisplacementRangeIterator {
+        matchings: perfect_matchings(displacement_restriction(&targets, n, 1, d)),
+        bound: Bound::Max,
+        n,
+        d,
+        generated: 0,
+        total: OnceLock::new(),
+    }
+}
+
+/// Derange the range of 0 to n (non-inclusive) while moving each element at least d positions.
+///
+/// These are the derangements of `derangements_with_min_displacement` for the range, in the same
+/// lexicographic order: they are built directly by never placing an element closer than d to its
+/// own position.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `d`: usize integer that determines the smallest displacement of an element
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::derangements_with_min_displacement_by_range;
+/// assert_equal(derangements_with_min_displacement_by_range(4, 2), [[2, 3, 0, 1]]);
+/// assert_eq!(derangements_with_min_displacement_by_range(6, 2).count(), 29);
+/// ```
+pub fn derangements_with_min_displacement_by_range(
+    n: usize,
+    d: usize,
+) -> DisplacementRangeIterator {
+    let targets = (0..n).collect::<Vec<_>>();
+    DisplacementRangeIterator {
+        matchings: perfect_matchings(displacement_restriction(&targets, n, d.max(1), usize::MAX)),
+        bound: Bound::Min,
+        n,
+        d,
+        generated: 0,
+        total: OnceLock::new(),
+    }
+}
+
+impl Iterator for DisplacementRangeIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.matchings.next();
+        if next.is_some() {
+            self.generated += 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining())
+    }
+}
+
+impl DisplacementRangeIterator {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    /// or d is too large to count (see `count_derangements_with_max_displacement` and
+    /// `count_derangements_with_min_displacement`).
+    ///
+    /// The total is only counted on the first call, so creating the iterator is always cheap.
+    pub fn remaining(&self) -> Option<u128> {
+        let total = self.total.get_or_init(|| match self.bound {
+            Bound::Max => counting::max_displacement_derangements(self.n, self.d),
+            Bound::Min => counting::min_displacement_derangements(self.n, self.d),
+        });
+        total.and_then(|total| total.checked_sub(self.generated))
+    }
+}
+
+impl FusedIterator for DisplacementRangeIterator {}
+
+/// Derange k or all elements of an iterable while moving each element at most d positions away
+/// from the index that equals it.
+///
+/// As for `derangements`, an element can't be at the index that equals it, and the permutations
+/// are built directly by only placing elements within d of that index.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to derange
+/// * `k`: `usize` integer that determines how many elements each derangement should have
+/// * `d`: usize integer that determines the largest displacement of an element
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::derangements_with_max_displacement;
+/// assert_equal(derangements_with_max_displacement(vec![3usize, 1, 0, 2].into_iter(), 4, 1),
+///     [[1, 0, 3, 2]]);
+///
+/// // Values that are outside the range of the indices can only be placed close to it
+/// assert_equal(derangements_with_max_displacement(vec![0usize, 1, 2, 7].into_iter(), 2, 1),
+///     [[1, 0], [1, 2]]);
+/// ```
+pub fn derangements_with_max_displacement<I>(
+    iter: I,
+    k: usize,
+    d: usize,
+) -> RestrictedPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let targets = values
+        .iter()
+        .map(|x| usize::from(x.clone()))
+        .collect::<Vec<_>>();
+    RestrictedPermutations::new(values, displacement_restriction(&targets, k, 1, d))
+}
+
+/// Derange k or all elements of an iterable while moving each element at least d positions away
+/// from the index that equals it.
+///
+/// As for `derangements`, an element can't be at the index that equals it, and the permutations
+/// are built directly by never placing an element closer than d to that index.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to derange
+/// * `k`: `usize` integer that determines how many elements each derangement should have
+/// * `d`: usize integer that determines the smallest displacement of an element
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::derangements_with_min_displacement;
+/// assert_equal(derangements_with_min_displacement(vec![0usize, 1, 2, 7].into_iter(), 3, 2),
+///     [[2, 7, 0]]);
+/// ```
+pub fn derangements_with_min_displacement<I>(
+    iter: I,
+    k: usize,
+    d: usize,
+) -> RestrictedPermutations<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let targets = values
+        .iter()
+        .map(|x| usize::from(x.clone()))
+        .collect::<Vec<_>>();
+    RestrictedPermutations::new(
+        values,
+        displacement_restriction(&targets, k, d.max(1), usize::MAX),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{derangements, fast_permutations};
+    use itertools::{assert_equal, Itertools};
+
+    fn displacements(x: &[usize]) -> impl Iterator<Item = usize> + '_ {
+        x.iter().enumerate().map(|(i, &v)| i.abs_diff(v))
+    }
+
+    #[test]
+    fn test_displacement_by_range_brute_force() {
+        for n in 0..=8 {
+            for d in 0..=n + 1 {
+                let expected = fast_permutations(0..n, n)
+                    .filter(|x| displacements(x).all(|x| (1..=d).contains(&x)))
+                    .sorted()
+                    .collect_vec();
+                let iter = derangements_with_max_displacement_by_range(n, d);
+                assert_eq!(iter.remaining(), Some(expected.len() as u128));
+                assert_equal(iter, expected);
+
+                let expected = fast_permutations(0..n, n)
+                    .filter(|x| displacements(x).all(|x| x >= d.max(1)))
+                    .sorted()
+                    .collect_vec();
+                let iter = derangements_with_min_displacement_by_range(n, d);
+                assert_eq!(iter.remaining(), Some(expected.len() as u128));
+                assert_equal(iter, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_displacement_iterable() {
+        let values = vec![0usize, 1, 1, 3, 4, 7];
+        for k in 0..=values.len() {
+            for d in 0..=4 {
+                let all = derangements(values.clone().into_iter(), k).collect_vec();
+                assert_equal(
+                    derangements_with_max_displacement(values.clone().into_iter(), k, d),
+                    all.iter()
+                        .filter(|x| displacements(x).all(|x| x <= d))
+                        .cloned(),
+                );
+                assert_equal(
+                    derangements_with_min_displacement(values.clone().into_iter(), k, d),
+                    all.iter()
+                        .filter(|x| displacements(x).all(|x| x >= d))
+                        .cloned(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_displacement_by_range_matches_iterable() {
+        for n in 0..=7 {
+            for d in 0..=n {
+                assert_equal(
+                    derangements_with_max_displacement_by_range(n, d),
+                    derangements_with_max_displacement(0..n, n, d),
+                );
+                assert_equal(
+                    derangements_with_min_displacement_by_range(n, d),
+                    derangements_with_min_displacement(0..n, n, d),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_displacement_too_large_to_count() {
+        // The transfer matrix only counts up to d = 6
+        assert!(crate::count_derangements_with_max_displacement(30, 6).is_some());
+        assert_eq!(crate::count_derangements_with_max_displacement(30, 7), None);
+        assert_eq!(
+            derangements_with_max_displacement_by_range(30, 7).remaining(),
+            None
+        );
+
+        // Any d of at least n - 1 allows every derangement, of which there are too many for 40
+        assert_eq!(
+            counting::max_displacement_derangements::<u128>(34, 33),
+            counting::subfactorial(34)
+        );
+        assert!(counting::subfactorial::<u128>(34).is_some());
+        assert_eq!(
+            counting::max_displacement_derangements::<u128>(40, 39),
+            None
+        );
+        assert_eq!(
+            derangements_with_max_displacement_by_range(40, 45).remaining(),
+            None
+        );
+
+        // Too large to count, which doesn't keep the derangements from being generated
+        let mut iter = derangements_with_max_displacement_by_range(30, 12);
+        let first = iter.next().unwrap();
+        assert!(displacements(&first).all(|x| (1..=12).contains(&x)));
+        assert_eq!(iter.remaining(), None);
+
+        let iter = derangements_with_min_displacement_by_range(40, 15);
+        assert_eq!(iter.remaining(), None);
+        assert_eq!(iter.size_hint(), (usize::MAX, None));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = derangements_with_max_displacement_by_range(9, 3);
+        iter.nth(50);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: DisplacementRangeIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let resume = |x| serde_json::from_value::<DisplacementRangeIterator>(x).is_err();
+        let mut iter = derangements_with_max_displacement_by_range(9, 3);
+        iter.nth(50);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(!resume(state.clone()));
+
+        let mut corrupted = state.clone();
+        corrupted["n"] = 10.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["d"] = 2.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["bound"] = "Min".into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["generated"] = 100000.into();
+        assert!(resume(corrupted));
+    }
+}
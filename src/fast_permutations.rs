@@ -1,17 +1,85 @@
+use crate::counting;
 use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "FastPermutationsState<I>"
+    )
+)]
 pub struct FastPermutations<I: Iterator> {
     buffer: Vec<usize>,
     values: Vec<I::Item>,
+    selection: Vec<usize>,
+    current: Vec<I::Item>,
     start: bool,
     index: usize,
-    k: usize,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `FastPermutations`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct FastPermutationsState<I: Iterator> {
+    buffer: Vec<usize>,
+    values: Vec<I::Item>,
+    selection: Vec<usize>,
+    current: Vec<I::Item>,
+    start: bool,
+    index: usize,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<FastPermutationsState<I>> for FastPermutations<I> {
+    type Error = &'static str;
+
+    fn try_from(state: FastPermutationsState<I>) -> Result<Self, Self::Error> {
+        let (n, k) = (state.values.len(), state.selection.len());
+        if state.buffer.len() != k || state.current.len() != k || state.index >= k.max(1) {
+            return Err("buffers of a different length than the selection");
+        }
+        if !state.selection.iter().tuple_windows().all(|(a, b)| a < b)
+            || state.selection.last().is_some_and(|&last| last >= n)
+        {
+            return Err("selection is not an increasing subset of the values");
+        }
+        if !state.buffer.iter().copied().sorted().eq(0..k) {
+            return Err("buffer is not a permutation of the selection");
+        }
+        if let (Some(remaining), Some(total)) = (state.remaining, counting::permutations(n, k)) {
+            if remaining > total {
+                return Err("more permutations remaining than there are in total");
+            }
+        }
+        Ok(FastPermutations {
+            buffer: state.buffer,
+            values: state.values,
+            selection: state.selection,
+            current: state.current,
+            start: state.start,
+            index: state.index,
+            remaining: state.remaining,
+        })
+    }
 }
 
 /// Permute k or all elements of an iterable.
 ///
+/// Each ordered selection of k elements (by position in the input) is generated exactly once: the
+/// k-subsets are visited in lexicographic order, and the elements of each subset are permuted with
+/// the same prefix-shift algorithm as `distinct_permutations`.
+///
 /// # Arguments
 ///
 /// * `iterable`: the iterable of items to permute
@@ -34,9 +102,9 @@ pub struct FastPermutations<I: Iterator> {
 /// assert_equal(fast_permutations(vec![0usize, 1, 1].into_iter(), 3),
 /// [[0, 1, 1], [1, 0, 1], [0, 1, 1], [1, 0, 1], [1, 1, 0], [1, 1, 0]]);
 ///
-/// // The length of the derangements can be shorter than the input iterable
+/// // The length of the permutations can be shorter than the input iterable
 /// assert_equal(fast_permutations(vec![0usize, 1, 2].into_iter(), 2),
-/// [[0, 1], [2, 0], [0, 2], [1, 0], [2, 1], [1, 2]]);
+/// [[0, 1], [1, 0], [0, 2], [2, 0], [1, 2], [2, 1]]);
 ///
 /// ```
 pub fn fast_permutations<I>(iter: I, k: usize) -> FastPermutations<I>
@@ -46,14 +114,18 @@ where
 {
     let mut values = Vec::from_iter(iter);
     values.sort_unstable();
-    let length = values.len();
-    let buffer = (0..length).rev().collect_vec();
+    // If k exceeds the number of values, there are no selections at all
+    let remaining = counting::permutations(values.len(), k);
+    let start = k <= values.len();
+    let k = if start { k } else { 0 };
     FastPermutations {
-        buffer,
+        buffer: (0..k).rev().collect_vec(),
+        current: values[0..k].to_owned(),
         values,
-        start: true,
-        index: length.saturating_sub(2),
-        k,
+        selection: (0..k).collect_vec(),
+        start,
+        index: k.saturating_sub(2),
+        remaining,
     }
 }
 
@@ -65,29 +137,16 @@ where
     pub fn get_values(&self) -> Vec<I::Item> {
         self.values.clone()
     }
-}
-
-impl<I> Iterator for FastPermutations<I>
-where
-    I: Iterator,
-    I::Item: Ord + Copy + Clone,
-{
-    type Item = Vec<I::Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Start iteration with buffer itself
-        if self.start {
-            self.start = false;
-            return Some(self.values[0..self.k].to_owned());
-        }
 
+    /// Move to the next permutation of the current selection; returns false if there is none.
+    fn shift(&mut self) -> bool {
         // Exhausted iteration
         let has_two_next = self.index + 2 < self.buffer.len();
         if !has_two_next
             && (self.buffer.len() <= self.index + 1
                 || self.buffer[0] <= self.buffer[self.index + 1])
         {
-            return None;
+            return false;
         }
 
         // Determine shift index
@@ -100,15 +159,15 @@ where
 
         // Prefix shift
         let shift_elem = self.buffer[shift_index];
-        let shift_val = self.values[shift_index];
+        let shift_val = self.current[shift_index].clone();
         let mut swap_index = shift_index;
         while swap_index > 0 {
             self.buffer[swap_index] = self.buffer[swap_index - 1];
-            self.values[swap_index] = self.values[swap_index - 1];
+            self.current[swap_index] = self.current[swap_index - 1].clone();
             swap_index -= 1;
         }
         self.buffer[0] = shift_elem;
-        self.values[0] = shift_val;
+        self.current[0] = shift_val;
 
         // Update index
         if self.buffer[0] < self.buffer[1] {
@@ -116,16 +175,130 @@ where
         } else {
             self.index += 1;
         }
+        true
+    }
+
+    /// Move to the next k-subset of the values; returns false if there is none.
+    fn next_selection(&mut self) -> bool {
+        let n = self.values.len();
+        let k = self.selection.len();
+        let Some(i) = (0..k).rev().find(|&i| self.selection[i] < n - k + i) else {
+            return false;
+        };
+        self.selection[i] += 1;
+        for j in i + 1..k {
+            self.selection[j] = self.selection[j - 1] + 1;
+        }
+
+        // Restart the prefix shift on the new subset
+        self.current = self
+            .selection
+            .iter()
+            .map(|&x| self.values[x].clone())
+            .collect_vec();
+        self.buffer = (0..k).rev().collect_vec();
+        self.index = k.saturating_sub(2);
+        true
+    }
+}
+
+impl<I> Iterator for FastPermutations<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        // Start iteration with buffer itself, and otherwise continue with the next subset once
+        // all orderings of the current one are done
+        if self.start {
+            self.start = false;
+        } else if !self.shift() && !self.next_selection() {
+            return None;
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(self.current.clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+/// `len` panics if the number of permutations left does not fit in a `usize`; on 64-bit targets,
+/// 20! is the largest factorial that does.
+impl<I> ExactSizeIterator for FastPermutations<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+{
+}
 
-        Some(self.values[0..self.k].to_owned())
+impl<I: Iterator> FastPermutations<I> {
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "DistinctPermutationsState<I>"
+    )
+)]
 pub struct DistinctPermutations<I: Iterator> {
     buffer: Vec<I::Item>,
     start: bool,
     index: usize,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `DistinctPermutations`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct DistinctPermutationsState<I: Iterator> {
+    buffer: Vec<I::Item>,
+    start: bool,
+    index: usize,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<DistinctPermutationsState<I>> for DistinctPermutations<I> {
+    type Error = &'static str;
+
+    fn try_from(state: DistinctPermutationsState<I>) -> Result<Self, Self::Error> {
+        let n = state.buffer.len();
+        if state.index >= n.max(1) {
+            return Err("index out of range of the buffer");
+        }
+        let total = counting::permutations(n, n);
+        if let (Some(remaining), Some(total)) = (state.remaining, total) {
+            if remaining > total {
+                return Err("more permutations remaining than there are in total");
+            }
+        }
+        Ok(DistinctPermutations {
+            buffer: state.buffer,
+            start: state.start,
+            index: state.index,
+            remaining: state.remaining,
+        })
+    }
 }
 
 /// Permute k or all elements of an iterable without repetitions.
@@ -153,10 +326,12 @@ where
     let mut buffer = Vec::from_iter(iter);
     buffer.sort_unstable_by(|a, b| b.cmp(a));
     let length = buffer.len();
+    let multiplicities = buffer.iter().dedup_with_count().map(|x| x.0).collect_vec();
     DistinctPermutations {
         buffer,
         start: true,
         index: length.saturating_sub(2),
+        remaining: counting::multinomial(&multiplicities),
     }
 }
 
@@ -168,9 +343,13 @@ where
     type Item = Vec<I::Item>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
         // Start iteration with buffer itself
         if self.start {
             self.start = false;
+            self.decrement();
             return Some(self.buffer.clone());
         }
 
@@ -207,8 +386,87 @@ where
             self.index += 1;
         }
 
+        self.decrement();
         Some(self.buffer.clone())
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+/// Repeated values make for fewer permutations, but `len` still panics if their number does not fit
+/// in a `usize`.
+impl<I> ExactSizeIterator for DistinctPermutations<I>
+where
+    I: Iterator,
+    I::Item: Ord + Copy + Clone,
+{
+}
+
+impl<I: Iterator> DistinctPermutations<I> {
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+
+    pub(crate) fn values(&self) -> &[I::Item] {
+        &self.buffer
+    }
+
+    fn decrement(&mut self) {
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+    }
+}
+
+impl<I> DistinctPermutations<I>
+where
+    I: Iterator,
+    I::Item: Ord + Clone,
+{
+    /// The lengths p, longest first, for which the permutation that was just returned starts a
+    /// run through all distinct permutations of its first p values, with the rest unchanged.
+    ///
+    /// Such a run starts with the smallest of these values followed by the others in
+    /// non-increasing order. As long as a larger value comes right after them, the prefix shifts
+    /// go through all other orders of the first p values and end with them in non-increasing
+    /// order, after which the value after them is shifted to the front.
+    pub(crate) fn runs(&self) -> impl Iterator<Item = usize> + '_ {
+        let buffer = &self.buffer;
+        let descending = buffer
+            .iter()
+            .skip(1)
+            .tuple_windows()
+            .take_while(|(a, b)| a >= b)
+            .count();
+        (2..=(descending + 2).min(buffer.len().saturating_sub(1)))
+            .rev()
+            .filter(move |&p| {
+                buffer[0] < buffer[1] && buffer[0] <= buffer[p - 1] && buffer[0] < buffer[p]
+            })
+    }
+
+    /// Jump to the last permutation of the run of length p (see `runs`) that starts at the
+    /// permutation that was just returned.
+    pub(crate) fn skip_run(&mut self, p: usize) {
+        let multiplicities = self.buffer[..p]
+            .iter()
+            .sorted()
+            .dedup_with_count()
+            .map(|x| x.0)
+            .collect_vec();
+        self.buffer[..p].sort_unstable_by(|a, b| b.cmp(a));
+        // The values up to p are now the longest non-increasing prefix
+        self.index = p - 1;
+        if let (Some(remaining), Some(run)) = (
+            self.remaining.as_mut(),
+            counting::multinomial::<u128>(&multiplicities),
+        ) {
+            *remaining -= run - 1;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -226,6 +484,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_k_permutations_range() {
+        for n in 0..=8 {
+            for k in 0..=n + 1 {
+                assert_equal(
+                    fast_permutations(0..n, k).sorted(),
+                    Itertools::permutations(0..n, k).sorted(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_k_permutations_repeated() {
+        let values = vec![2u8, 0, 2, 5, 1, 0, 2];
+        for k in 0..=values.len() {
+            assert_equal(
+                fast_permutations(values.clone().into_iter(), k).sorted(),
+                Itertools::permutations(values.clone().into_iter(), k).sorted(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_size_hint() {
+        for k in 0..=6 {
+            let mut iter = fast_permutations(vec![3, 1, 4, 1, 5].into_iter(), k);
+            let mut count = iter.clone().count();
+            loop {
+                assert_eq!(iter.remaining(), Some(count as u128));
+                assert_eq!(iter.len(), count);
+                if iter.next().is_none() {
+                    break;
+                }
+                count -= 1;
+            }
+        }
+        let mut iter = distinct_permutations(vec![3, 1, 4, 1, 5, 9, 2, 6, 5, 3].into_iter());
+        let mut count = iter.clone().count();
+        loop {
+            assert_eq!(iter.remaining(), Some(count as u128));
+            assert_eq!(iter.len(), count);
+            if iter.next().is_none() {
+                break;
+            }
+            count -= 1;
+        }
+        assert_eq!(fast_permutations(0..40, 40).size_hint(), (usize::MAX, None));
+    }
+
     #[test]
     fn test_permutations_manual() {
         assert_equal(
@@ -245,7 +553,7 @@ mod tests {
         );
         assert_equal(
             fast_permutations(vec![0usize, 1, 3].into_iter(), 2),
-            [[0, 1], [3, 0], [0, 3], [1, 0], [3, 1], [1, 3]],
+            [[0, 1], [1, 0], [0, 3], [3, 0], [1, 3], [3, 1]],
         );
         assert_equal(
             fast_permutations(vec![0u16, 1, 1].into_iter(), 3),
@@ -282,4 +590,58 @@ mod tests {
             [[1, 1, 0], [0, 1, 1], [1, 0, 1]],
         );
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        for k in [3, 6] {
+            let mut iter = fast_permutations(0..6usize, k);
+            iter.nth(57);
+            let json = serde_json::to_string(&iter).unwrap();
+            let resumed: FastPermutations<std::ops::Range<usize>> =
+                serde_json::from_str(&json).unwrap();
+            assert_eq!(resumed.remaining(), iter.remaining());
+            assert_equal(resumed, iter);
+        }
+
+        let mut iter = distinct_permutations(vec![0, 0, 1, 2, 2, 2].into_iter());
+        iter.nth(20);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: DistinctPermutations<std::vec::IntoIter<i32>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        type Permutations = FastPermutations<std::ops::Range<usize>>;
+        let mut iter = fast_permutations(0..6usize, 4);
+        iter.nth(57);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(serde_json::from_value::<Permutations>(state.clone()).is_ok());
+
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 1000.into();
+        assert!(serde_json::from_value::<Permutations>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["buffer"][0] = 7.into();
+        assert!(serde_json::from_value::<Permutations>(corrupted).is_err());
+        let mut corrupted = state.clone();
+        corrupted["selection"][3] = 6.into();
+        assert!(serde_json::from_value::<Permutations>(corrupted).is_err());
+        let mut corrupted = state;
+        corrupted["index"] = usize::MAX.into();
+        assert!(serde_json::from_value::<Permutations>(corrupted).is_err());
+
+        let mut iter = distinct_permutations(vec![0, 0, 1, 2].into_iter());
+        iter.next();
+        let mut corrupted = serde_json::to_value(&iter).unwrap();
+        corrupted["remaining"] = 30.into();
+        assert!(
+            serde_json::from_value::<DistinctPermutations<std::vec::IntoIter<i32>>>(corrupted)
+                .is_err()
+        );
+    }
 }
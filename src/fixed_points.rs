@@ -0,0 +1,746 @@
+use crate::counting;
+use crate::derangements_range::{derangements_by_range, DerangementsRangeIterator};
+use crate::restriction::{perfect_matchings, PerfectMatchings, Restriction};
+use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::iter::FusedIterator;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "FixedPointsRangeState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct FixedPointsRangeIterator {
+    n: usize,
+    max: usize,
+    fixed: Vec<usize>,
+    free: Vec<usize>,
+    derangements: DerangementsRangeIterator,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `FixedPointsRangeIterator`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct FixedPointsRangeState {
+    n: usize,
+    max: usize,
+    fixed: Vec<usize>,
+    free: Vec<usize>,
+    derangements: DerangementsRangeIterator,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<FixedPointsRangeState> for FixedPointsRangeIterator {
+    type Error = &'static str;
+
+    fn try_from(state: FixedPointsRangeState) -> Result<Self, Self::Error> {
+        let (n, m) = (state.n, state.fixed.len());
+        if state.max > n || m > state.max + 1 {
+            return Err("more fixed points than there are elements");
+        }
+        if !state.fixed.iter().tuple_windows().all(|(a, b)| a < b)
+            || state.fixed.last().is_some_and(|&last| last >= n)
+        {
+            return Err("fixed points are not an increasing subset of the range");
+        }
+        let fixed = &state.fixed;
+        if !(0..n)
+            .filter(|x| !fixed.contains(x))
+            .eq(state.free.iter().copied())
+            || state.derangements.n() != state.free.len()
+        {
+            return Err("free elements don't match the fixed points");
+        }
+
+        // The derangements of the free elements that are left, then those for the later sets of
+        // fixed points of the same size, and then all permutations with more fixed points
+        let expected = || {
+            if m > state.max {
+                return Some(0);
+            }
+            let later = fixed.iter().enumerate().try_fold(0u128, |acc, (i, &x)| {
+                acc.checked_add(counting::binomial(n - 1 - x, m - i)?)
+            })?;
+            let first = later.checked_mul(counting::subfactorial(n - m)?)?;
+            (m + 1..=state.max).try_fold(
+                state.derangements.remaining()?.checked_add(first)?,
+                |acc, j| acc.checked_add(counting::rencontres::<u128>(n, j)?),
+            )
+        };
+        if state.remaining.is_some() && state.remaining != expected() {
+            return Err("remaining permutations don't match the fixed points");
+        }
+        Ok(FixedPointsRangeIterator {
+            n,
+            max: state.max,
+            fixed: state.fixed,
+            free: state.free,
+            derangements: state.derangements,
+            remaining: state.remaining,
+        })
+    }
+}
+
+fn fixed_points_by_range(n: usize, min: usize, max: usize) -> FixedPointsRangeIterator {
+    let max = max.min(n);
+    let remaining = (min..=max).try_fold(0u128, |acc, m| {
+        acc.checked_add(counting::rencontres::<u128>(n, m)?)
+    });
+    let mut iter = FixedPointsRangeIterator {
+        n,
+        max,
+        fixed: (0..min.min(max + 1)).collect(),
+        free: vec![],
+        derangements: derangements_by_range(0),
+        remaining,
+    };
+    iter.select();
+    iter
+}
+
+/// Permute the range of 0 to n (non-inclusive) while keeping exactly m elements in place.
+///
+/// Each permutation is generated directly, by choosing the m fixed points (in lexicographic order)
+/// and deranging the other elements with `derangements_by_range`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `m`: usize integer that determines the number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::permutations_with_fixed_points_by_range;
+/// assert_equal(permutations_with_fixed_points_by_range(3, 1), [[0, 2, 1], [2, 1, 0], [1, 0, 2]]);
+///
+/// // No fixed points are the derangements, and all fixed points the identity
+/// assert_equal(permutations_with_fixed_points_by_range(3, 0), [[2, 0, 1], [1, 2, 0]]);
+/// assert_equal(permutations_with_fixed_points_by_range(3, 3), [[0, 1, 2]]);
+/// ```
+pub fn permutations_with_fixed_points_by_range(n: usize, m: usize) -> FixedPointsRangeIterator {
+    fixed_points_by_range(n, m, m)
+}
+
+/// Permute the range of 0 to n (non-inclusive) while keeping at most m elements in place.
+///
+/// The permutations are generated in order of their number of fixed points, as for
+/// `permutations_with_fixed_points_by_range`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `m`: usize integer that determines the maximum number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::permutations_with_at_most_fixed_points_by_range;
+/// assert_equal(permutations_with_at_most_fixed_points_by_range(3, 1),
+///     [[2, 0, 1], [1, 2, 0], [0, 2, 1], [2, 1, 0], [1, 0, 2]]);
+/// ```
+pub fn permutations_with_at_most_fixed_points_by_range(
+    n: usize,
+    m: usize,
+) -> FixedPointsRangeIterator {
+    fixed_points_by_range(n, 0, m)
+}
+
+/// Permute the range of 0 to n (non-inclusive) while keeping at least m elements in place.
+///
+/// The permutations are generated in order of their number of fixed points, as for
+/// `permutations_with_fixed_points_by_range`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `m`: usize integer that determines the minimum number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::permutations_with_at_least_fixed_points_by_range;
+/// assert_equal(permutations_with_at_least_fixed_points_by_range(3, 1),
+///     [[0, 2, 1], [2, 1, 0], [1, 0, 2], [0, 1, 2]]);
+/// ```
+pub fn permutations_with_at_least_fixed_points_by_range(
+    n: usize,
+    m: usize,
+) -> FixedPointsRangeIterator {
+    fixed_points_by_range(n, m, n)
+}
+
+impl FixedPointsRangeIterator {
+    /// Start deranging the elements that are not one of the current fixed points.
+    fn select(&mut self) {
+        let fixed = &self.fixed;
+        self.free = (0..self.n).filter(|x| !fixed.contains(x)).collect();
+        self.derangements = derangements_by_range(self.free.len());
+    }
+
+    /// Move to the next set of fixed points of the same size, in lexicographic order.
+    fn next_fixed(&mut self) -> bool {
+        let m = self.fixed.len();
+        match (0..m).rev().find(|&i| self.fixed[i] < self.n - m + i) {
+            Some(i) => {
+                self.fixed[i] += 1;
+                for j in i + 1..m {
+                    self.fixed[j] = self.fixed[j - 1] + 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Iterator for FixedPointsRangeIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.fixed.len() > self.max {
+                return None;
+            }
+            if let Some(derangement) = self.derangements.next() {
+                let mut permutation = (0..self.n).collect::<Vec<_>>();
+                for (i, &x) in derangement.iter().enumerate() {
+                    permutation[self.free[i]] = self.free[x];
+                }
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                return Some(permutation);
+            }
+
+            // Go to the next set of fixed points, or to the first one with an extra fixed point
+            if !self.next_fixed() {
+                self.fixed = (0..self.fixed.len() + 1).collect();
+            }
+            self.select();
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl FixedPointsRangeIterator {
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+}
+
+impl FusedIterator for FixedPointsRangeIterator {}
+
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "PermutationsWithFixedPointsState<I>"
+    )
+)]
+pub struct PermutationsWithFixedPoints<I: Iterator> {
+    values: Vec<I::Item>,
+    fixed: Vec<usize>,
+    fixable: Vec<usize>,
+    chosen: Vec<usize>,
+    max: usize,
+    matchings: PerfectMatchings,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `PermutationsWithFixedPoints`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct PermutationsWithFixedPointsState<I: Iterator> {
+    values: Vec<I::Item>,
+    fixed: Vec<usize>,
+    fixable: Vec<usize>,
+    chosen: Vec<usize>,
+    max: usize,
+    matchings: PerfectMatchings,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<PermutationsWithFixedPointsState<I>> for PermutationsWithFixedPoints<I> {
+    type Error = &'static str;
+
+    fn try_from(state: PermutationsWithFixedPointsState<I>) -> Result<Self, Self::Error> {
+        let n = state.values.len();
+        if state.fixed.len() != n || !state.fixed.iter().tuple_windows().all(|(a, b)| a <= b) {
+            return Err("values don't match their sorted indices");
+        }
+        let own = state.fixed.iter().filter(|&&x| x < n).counts();
+        if !own
+            .keys()
+            .map(|&&x| x)
+            .sorted()
+            .eq(state.fixable.iter().copied())
+        {
+            return Err("fixable positions don't match the values");
+        }
+        let k = state.fixable.len();
+        if state.max > k
+            || state.chosen.len() > state.max + 1
+            || !state.chosen.iter().tuple_windows().all(|(a, b)| a < b)
+            || state.chosen.last().is_some_and(|&last| last >= k)
+        {
+            return Err("chosen positions out of range of the fixable positions");
+        }
+        if state.chosen.len() <= state.max {
+            let positions = state.chosen.iter().map(|&i| state.fixable[i]).collect_vec();
+            if *state.matchings.restriction() != fixed_restriction(&state.fixed, &positions) {
+                return Err("permutations don't match the chosen fixed points");
+            }
+        }
+        let multiplicities = own.into_values().collect_vec();
+        let total = (0..=state.max).try_fold(0u128, |acc, m| {
+            acc.checked_add(counting::own_rencontres::<u128>(n, m, &multiplicities)?)
+        });
+        if let (Some(remaining), Some(total)) = (state.remaining, total) {
+            if remaining > total {
+                return Err("more remaining permutations than there are in total");
+            }
+        }
+        Ok(PermutationsWithFixedPoints {
+            values: state.values,
+            fixed: state.fixed,
+            fixable: state.fixable,
+            chosen: state.chosen,
+            max: state.max,
+            matchings: state.matchings,
+            remaining: state.remaining,
+        })
+    }
+}
+
+impl<I> Clone for PermutationsWithFixedPoints<I>
+where
+    I: Clone + Iterator,
+    I::Item: Clone,
+{
+    clone_fields!(values, fixed, fixable, chosen, max, matchings, remaining);
+}
+
+impl<I> Debug for PermutationsWithFixedPoints<I>
+where
+    I: Iterator + Debug,
+    I::Item: Debug,
+{
+    debug_fmt_fields!(
+        PermutationsWithFixedPoints,
+        values,
+        fixed,
+        fixable,
+        chosen,
+        max,
+        matchings,
+        remaining
+    );
+}
+
+fn fixed_points<I>(iter: I, min: usize, max: usize) -> PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let fixed = values.iter().map(|x| usize::from(x.clone())).collect_vec();
+
+    // Only the positions that equal one of the values can be fixed points
+    let n = values.len();
+    let own = fixed.iter().filter(|&&x| x < n).counts();
+    let fixable = own.keys().map(|&&x| x).sorted().collect_vec();
+    let multiplicities = own.into_values().collect_vec();
+    let max = max.min(fixable.len());
+    let remaining = (min..=max).try_fold(0u128, |acc, m| {
+        acc.checked_add(counting::own_rencontres::<u128>(n, m, &multiplicities)?)
+    });
+    let mut iter = PermutationsWithFixedPoints {
+        values,
+        fixed,
+        fixable,
+        chosen: (0..min.min(max + 1)).collect(),
+        max,
+        matchings: perfect_matchings(Restriction::new(0, 0)),
+        remaining,
+    };
+    iter.select();
+    iter
+}
+
+/// Permute all elements of an iterable while keeping exactly m of them in place.
+///
+/// As for `derangements`, an element is in place if it equals its index, so without fixed points
+/// these are the derangements of the iterable. Each permutation is generated directly, by
+/// choosing the m positions that hold their own value (in lexicographic order) and placing the
+/// other values with the same backtracking as `derangements`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `m`: usize integer that determines the number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{derangements, permutations_with_fixed_points};
+/// assert_equal(permutations_with_fixed_points(vec![0usize, 1, 5].into_iter(), 1),
+///     [[0, 5, 1], [5, 1, 0]]);
+///
+/// // No fixed points are the derangements
+/// assert_equal(permutations_with_fixed_points(vec![0usize, 1, 5].into_iter(), 0),
+///     derangements(vec![0usize, 1, 5].into_iter(), 3));
+/// ```
+pub fn permutations_with_fixed_points<I>(iter: I, m: usize) -> PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    fixed_points(iter, m, m)
+}
+
+/// Permute all elements of an iterable while keeping at most m of them in place.
+///
+/// Fixed points are elements that equal their index, as for `permutations_with_fixed_points`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `m`: usize integer that determines the maximum number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::permutations_with_at_most_fixed_points;
+/// assert_equal(permutations_with_at_most_fixed_points(vec![0usize, 1].into_iter(), 1), [[1, 0]]);
+/// ```
+pub fn permutations_with_at_most_fixed_points<I>(
+    iter: I,
+    m: usize,
+) -> PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    fixed_points(iter, 0, m)
+}
+
+/// Permute all elements of an iterable while keeping at least m of them in place.
+///
+/// Fixed points are elements that equal their index, as for `permutations_with_fixed_points`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `m`: usize integer that determines the minimum number of fixed points
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::permutations_with_at_least_fixed_points;
+/// assert_equal(permutations_with_at_least_fixed_points(vec![0usize, 1, 2].into_iter(), 2),
+///     [[0, 1, 2]]);
+/// ```
+pub fn permutations_with_at_least_fixed_points<I>(
+    iter: I,
+    m: usize,
+) -> PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    usize: From<I::Item>,
+{
+    fixed_points(iter, m, usize::MAX)
+}
+
+/// The restriction where the values with the given indices can be at their own positions exactly
+/// when those positions are chosen as fixed points.
+fn fixed_restriction(fixed: &[usize], positions: &[usize]) -> Restriction {
+    Restriction::from_fn(fixed, fixed.len(), |position, &x| {
+        (x == position) == positions.binary_search(&position).is_ok()
+    })
+}
+
+impl<I: Iterator> PermutationsWithFixedPoints<I> {
+    /// Start the permutations where exactly the chosen positions hold one of their own values.
+    fn select(&mut self) {
+        if self.chosen.len() > self.max {
+            return;
+        }
+        let positions = self.chosen.iter().map(|&i| self.fixable[i]).collect_vec();
+        self.matchings = perfect_matchings(fixed_restriction(&self.fixed, &positions));
+    }
+
+    /// Move to the next choice of fixed positions of the same size, in lexicographic order.
+    fn next_chosen(&mut self) -> bool {
+        let (k, m) = (self.fixable.len(), self.chosen.len());
+        match (0..m).rev().find(|&i| self.chosen[i] < k - m + i) {
+            Some(i) => {
+                self.chosen[i] += 1;
+                for j in i + 1..m {
+                    self.chosen[j] = self.chosen[j - 1] + 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+}
+
+impl<I> Iterator for PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.chosen.len() > self.max || self.remaining == Some(0) {
+                return None;
+            }
+            if let Some(indices) = self.matchings.advance() {
+                let permutation = indices.iter().map(|&i| self.values[i].clone()).collect();
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                return Some(permutation);
+            }
+
+            // Go to the next choice of fixed positions, or to the first one with an extra one
+            if !self.next_chosen() {
+                self.chosen = (0..self.chosen.len() + 1).collect();
+            }
+            self.select();
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl<I> FusedIterator for PermutationsWithFixedPoints<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{derangements, fast_permutations};
+    use itertools::{assert_equal, Itertools};
+
+    fn fixed_points(x: &[usize]) -> usize {
+        x.iter().enumerate().filter(|(i, &v)| *i == v).count()
+    }
+
+    #[test]
+    fn test_brute_force() {
+        for n in 0..=8 {
+            let all = fast_permutations(0..n, n).collect_vec();
+            for m in 0..=n + 1 {
+                let filtered = |f: &dyn Fn(usize) -> bool| {
+                    all.iter()
+                        .filter(|x| f(fixed_points(x)))
+                        .cloned()
+                        .sorted()
+                        .collect_vec()
+                };
+                assert_equal(
+                    permutations_with_fixed_points_by_range(n, m).sorted(),
+                    filtered(&|x| x == m),
+                );
+                assert_equal(
+                    permutations_with_at_most_fixed_points_by_range(n, m).sorted(),
+                    filtered(&|x| x <= m),
+                );
+                assert_equal(
+                    permutations_with_at_least_fixed_points_by_range(n, m).sorted(),
+                    filtered(&|x| x >= m),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_iterable() {
+        // Repeated values, and values outside the range of the indices
+        for values in [vec![3usize, 0, 7, 1, 1, 4], vec![2, 2, 2, 0], vec![5, 6, 7]] {
+            let n = values.len();
+            let all = fast_permutations(values.clone().into_iter(), n).collect_vec();
+            for m in 0..=n + 1 {
+                let filtered = |f: &dyn Fn(usize) -> bool| {
+                    all.iter()
+                        .filter(|x| f(fixed_points(x)))
+                        .cloned()
+                        .sorted()
+                        .collect_vec()
+                };
+                let mut iters = [
+                    permutations_with_fixed_points(values.clone().into_iter(), m),
+                    permutations_with_at_most_fixed_points(values.clone().into_iter(), m),
+                    permutations_with_at_least_fixed_points(values.clone().into_iter(), m),
+                ];
+                for iter in &iters {
+                    assert_eq!(iter.remaining(), Some(iter.clone().count() as u128));
+                }
+                let [exactly, at_most, at_least] = &mut iters;
+                assert_equal(exactly.sorted(), filtered(&|x| x == m));
+                assert_equal(at_most.sorted(), filtered(&|x| x <= m));
+                assert_equal(at_least.sorted(), filtered(&|x| x >= m));
+            }
+
+            // Without fixed points, these are the derangements in the same order
+            assert_equal(
+                permutations_with_fixed_points(values.clone().into_iter(), 0),
+                derangements(values.into_iter(), n),
+            );
+        }
+    }
+
+    #[test]
+    fn test_size_hint() {
+        for n in 0..7 {
+            for m in 0..=n + 1 {
+                for mut iter in [
+                    permutations_with_fixed_points_by_range(n, m),
+                    permutations_with_at_most_fixed_points_by_range(n, m),
+                    permutations_with_at_least_fixed_points_by_range(n, m),
+                ] {
+                    let mut count = iter.clone().count();
+                    loop {
+                        assert_eq!(iter.remaining(), Some(count as u128));
+                        if iter.next().is_none() {
+                            break;
+                        }
+                        count -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = permutations_with_at_most_fixed_points_by_range(7, 3);
+        iter.nth(1000);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: FixedPointsRangeIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+
+        let mut iter =
+            permutations_with_at_least_fixed_points(vec![0usize, 1, 1, 3, 6].into_iter(), 1);
+        iter.nth(10);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: PermutationsWithFixedPoints<std::vec::IntoIter<usize>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let resume = |x| serde_json::from_value::<FixedPointsRangeIterator>(x).is_err();
+        let mut iter = permutations_with_at_least_fixed_points_by_range(5, 1);
+        loop {
+            assert!(!resume(serde_json::to_value(&iter).unwrap()));
+            if iter.next().is_none() {
+                break;
+            }
+        }
+
+        let mut iter = permutations_with_at_most_fixed_points_by_range(7, 3);
+        iter.nth(1000);
+        let state = serde_json::to_value(&iter).unwrap();
+
+        let mut corrupted = state.clone();
+        corrupted["free"] = vec![0, 9].into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 0.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["fixed"] = vec![3, 1].into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["max"] = 8.into();
+        assert!(resume(corrupted));
+
+        type Permutations = PermutationsWithFixedPoints<std::vec::IntoIter<usize>>;
+        let resume = |x| serde_json::from_value::<Permutations>(x).is_err();
+        let mut iter =
+            permutations_with_at_least_fixed_points(vec![0usize, 1, 1, 3].into_iter(), 1);
+        loop {
+            assert!(!resume(serde_json::to_value(&iter).unwrap()));
+            if iter.next().is_none() {
+                break;
+            }
+        }
+
+        let mut iter = permutations_with_fixed_points(vec![0usize, 1, 1, 3, 6].into_iter(), 1);
+        iter.next();
+        let state = serde_json::to_value(&iter).unwrap();
+
+        let mut corrupted = state.clone();
+        corrupted["chosen"] = vec![5].into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["chosen"] = vec![1].into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["fixable"] = vec![0, 1, 4].into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["remaining"] = 1000.into();
+        assert!(resume(corrupted));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::counting;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::iter::FusedIterator;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "InvolutionsRangeState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct InvolutionsRangeIterator {
+    /// The current matching in permutation form, where an element that is not paired yet points
+    /// to itself
+    involution: Vec<usize>,
+    /// The smaller element of each pair, in the order in which the pairs were made
+    pairs: Vec<usize>,
+    done: bool,
+    remaining: Option<u128>,
+}
+
+/// The fields of a deserialized `InvolutionsRangeIterator`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct InvolutionsRangeState {
+    involution: Vec<usize>,
+    pairs: Vec<usize>,
+    done: bool,
+    remaining: Option<u128>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<InvolutionsRangeState> for InvolutionsRangeIterator {
+    type Error = &'static str;
+
+    fn try_from(state: InvolutionsRangeState) -> Result<Self, Self::Error> {
+        let involution = &state.involution;
+        let n = involution.len();
+        if !involution
+            .iter()
+            .enumerate()
+            .all(|(i, &x)| x < n && involution[x] == i)
+        {
+            return Err("matching is not an involution");
+        }
+        if state.done {
+            if state.remaining.is_some_and(|remaining| remaining > 0) {
+                return Err("remaining derangements after the last one");
+            }
+        } else {
+            // The pairs are made with the smallest free element, so the matchings that are left
+            // are this one, and those with a larger partner for one of its pairs
+            let mut free = vec![true; n];
+            let mut expected = Some(1u128);
+            for &a in &state.pairs {
+                let b = involution.get(a).copied().unwrap_or(a);
+                if free.iter().position(|&x| x) != Some(a) || b <= a || !free[b] {
+                    return Err("pairs don't match the matching");
+                }
+                let later = free[b + 1..].iter().filter(|&&x| x).count();
+                let rest = free.iter().filter(|&&x| x).count() - 2;
+                expected = expected.and_then(|expected| {
+                    let count = counting::involutive_derangements::<u128>(rest)?;
+                    expected.checked_add((later as u128).checked_mul(count)?)
+                });
+                free[a] = false;
+                free[b] = false;
+            }
+            if free.contains(&true) {
+                return Err("pairs don't match the matching");
+            }
+            if state.remaining.is_some() && state.remaining != expected {
+                return Err("remaining derangements don't match the matching");
+            }
+        }
+        Ok(InvolutionsRangeIterator {
+            involution: state.involution,
+            pairs: state.pairs,
+            done: state.done,
+            remaining: state.remaining,
+        })
+    }
+}
+
+/// Derange the range of 0 to n (non-inclusive) into involutions: every element is swapped with
+/// another one, so the permutations are the perfect matchings of the range.
+///
+/// The smallest element that is not paired yet is paired with each larger free element in turn,
+/// which builds the (n - 1)!! matchings directly, in lexicographic order. For odd n there are none.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::involutive_derangements_by_range;
+/// assert_equal(involutive_derangements_by_range(4), [[1, 0, 3, 2], [2, 3, 0, 1], [3, 2, 1, 0]]);
+/// assert_eq!(involutive_derangements_by_range(5).count(), 0);
+/// ```
+pub fn involutive_derangements_by_range(n: usize) -> InvolutionsRangeIterator {
+    let mut iter = InvolutionsRangeIterator {
+        involution: (0..n).collect(),
+        pairs: Vec::with_capacity(n / 2),
+        done: n % 2 == 1,
+        remaining: counting::involutive_derangements(n),
+    };
+    iter.pair_remaining();
+    iter
+}
+
+impl InvolutionsRangeIterator {
+    /// The first free element after `start`, if any
+    fn next_free(&self, start: usize) -> Option<usize> {
+        (start + 1..self.involution.len()).find(|&x| self.involution[x] == x)
+    }
+
+    fn pair(&mut self, a: usize, b: usize) {
+        self.involution[a] = b;
+        self.involution[b] = a;
+        self.pairs.push(a);
+    }
+
+    /// Pair up the free elements in order, which is the smallest way to complete the matching
+    fn pair_remaining(&mut self) {
+        for a in 0..self.involution.len() {
+            if self.involution[a] == a {
+                match self.next_free(a) {
+                    Some(b) => self.pair(a, b),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    /// Move to the next matching: undo the last pairs until one of them can be paired with a
+    /// larger free element instead
+    fn advance(&mut self) {
+        while let Some(a) = self.pairs.pop() {
+            let b = self.involution[a];
+            self.involution[a] = a;
+            self.involution[b] = b;
+            if let Some(c) = self.next_free(b) {
+                self.pair(a, c);
+                self.pair_remaining();
+                return;
+            }
+        }
+        self.done = true;
+    }
+}
+
+impl Iterator for InvolutionsRangeIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.involution.clone();
+        self.advance();
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining)
+    }
+}
+
+impl InvolutionsRangeIterator {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.remaining
+    }
+}
+
+impl FusedIterator for InvolutionsRangeIterator {}
+
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "InvolutiveDerangementsState<I>"
+    )
+)]
+pub struct InvolutiveDerangements<I: Iterator> {
+    values: Vec<I::Item>,
+    indices: InvolutionsRangeIterator,
+}
+
+/// The fields of a deserialized `InvolutiveDerangements`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct InvolutiveDerangementsState<I: Iterator> {
+    values: Vec<I::Item>,
+    indices: InvolutionsRangeIterator,
+}
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<InvolutiveDerangementsState<I>> for InvolutiveDerangements<I> {
+    type Error = &'static str;
+
+    fn try_from(state: InvolutiveDerangementsState<I>) -> Result<Self, Self::Error> {
+        if state.values.len() != state.indices.involution.len() {
+            return Err("values of a different length than the matching");
+        }
+        Ok(InvolutiveDerangements {
+            values: state.values,
+            indices: state.indices,
+        })
+    }
+}
+
+impl<I> Clone for InvolutiveDerangements<I>
+where
+    I: Clone + Iterator,
+    I::Item: Clone,
+{
+    clone_fields!(values, indices);
+}
+
+impl<I> Debug for InvolutiveDerangements<I>
+where
+    I: Iterator + Debug,
+    I::Item: Debug,
+{
+    debug_fmt_fields!(InvolutiveDerangements, values, indices);
+}
+
+/// Permute all elements of an iterable such that every element swaps positions with another one.
+///
+/// Like the fixed points of `permutations_with_fixed_points`, the swaps are between positions in
+/// the input, so equal values at different positions can be swapped with each other.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to pair up
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::involutive_derangements;
+/// assert_equal(involutive_derangements(vec!["a", "b", "c", "d"].into_iter()),
+///     [["b", "a", "d", "c"], ["c", "d", "a", "b"], ["d", "c", "b", "a"]]);
+/// ```
+pub fn involutive_derangements<I>(iter: I) -> InvolutiveDerangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    let values = Vec::from_iter(iter);
+    let indices = involutive_derangements_by_range(values.len());
+    InvolutiveDerangements { values, indices }
+}
+
+impl<I> Iterator for InvolutiveDerangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let indices = self.indices.next()?;
+        Some(indices.iter().map(|&i| self.values[i].clone()).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.indices.size_hint()
+    }
+}
+
+impl<I> InvolutiveDerangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.indices.remaining()
+    }
+}
+
+impl<I> FusedIterator for InvolutiveDerangements<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derangements_by_range;
+    use itertools::{assert_equal, Itertools};
+
+    fn is_involution(x: &[usize]) -> bool {
+        x.iter().all(|&v| x[x[v]] == v)
+    }
+
+    #[test]
+    fn test_involutions_subset_of_derangements() {
+        for n in 0..=9 {
+            let expected = derangements_by_range(n)
+                .filter(|x| is_involution(x))
+                .sorted()
+                .collect_vec();
+            let iter = involutive_derangements_by_range(n);
+            assert_eq!(iter.remaining(), Some(expected.len() as u128));
+            assert_equal(iter, expected);
+        }
+    }
+
+    #[test]
+    fn test_involutions_count() {
+        // (n - 1)!! for even n
+        let expected = [1, 0, 1, 0, 3, 0, 15, 0, 105, 0, 945, 0, 10395];
+        for (n, &count) in expected.iter().enumerate() {
+            assert_eq!(involutive_derangements_by_range(n).count(), count);
+            assert_eq!(
+                counting::involutive_derangements::<u128>(n),
+                Some(count as u128)
+            );
+        }
+    }
+
+    #[test]
+    fn test_involutive_derangements_iterable() {
+        let values = vec!["a", "b", "b", "c", "d", "e"];
+        let expected = involutive_derangements_by_range(values.len())
+            .map(|x| x.into_iter().map(|i| values[i]).collect_vec())
+            .collect_vec();
+        let iter = involutive_derangements(values.clone().into_iter());
+        assert_eq!(iter.remaining(), Some(15));
+        assert_equal(iter, expected);
+        assert_equal(involutive_derangements(0..0), [Vec::<i32>::new()]);
+        assert_eq!(involutive_derangements(0..3).count(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = involutive_derangements_by_range(10);
+        iter.nth(300);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: InvolutionsRangeIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let resume = |x| serde_json::from_value::<InvolutionsRangeIterator>(x).is_err();
+        for n in [0, 3, 6] {
+            let mut iter = involutive_derangements_by_range(n);
+            loop {
+                assert!(!resume(serde_json::to_value(&iter).unwrap()));
+                if iter.next().is_none() {
+                    break;
+                }
+            }
+        }
+
+        let mut iter = involutive_derangements_by_range(10);
+        iter.nth(300);
+        let state = serde_json::to_value(&iter).unwrap();
+        let mut corrupted = state.clone();
+        corrupted["remaining"] = 0.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["involution"][0] = 10.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["involution"][0] = 0.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["pairs"][1] = 9.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["pairs"] = serde_json::Value::Array(vec![]);
+        assert!(resume(corrupted));
+
+        let mut iter = involutive_derangements(vec!["a", "b", "c", "d"].into_iter());
+        iter.next();
+        let mut corrupted = serde_json::to_value(&iter).unwrap();
+        corrupted["values"] = vec!["a", "b"].into();
+        assert!(
+            serde_json::from_value::<InvolutiveDerangements<std::vec::IntoIter<String>>>(corrupted)
+                .is_err()
+        );
+    }
+}
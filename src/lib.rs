@@ -17,20 +17,113 @@
 //!    [[1, 2, 0], [1, 2, 3], [1, 3, 0], [1, 3, 2], [2, 3, 0], [3, 2, 0]]);
 //! ```
 
+#[macro_use]
+mod macros;
+
+mod backtracking;
+mod counting;
+mod cycles;
 mod derangements;
 mod derangements_range;
+mod displacement;
 mod fast_permutations;
+mod fixed_points;
+mod involutions;
+mod pairs;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod permanent;
+mod permutation;
+#[cfg(feature = "rand")]
+mod random;
+mod ranking;
 mod restricted_permutations;
+mod restriction;
+mod shard;
+mod window;
 
 pub use derangements_range::derangements_by_range;
+pub use derangements_range::even_derangements_by_range;
+pub use derangements_range::odd_derangements_by_range;
+pub use permutation::derangements_by_range_perm;
+pub use window::derangements_by_range_window;
+
+pub use cycles::cyclic_permutations;
+pub use cycles::cyclic_permutations_by_range;
+pub use cycles::derangements_by_cycle_type;
+pub use cycles::CycleSpec;
+
+pub use displacement::derangements_with_max_displacement;
+pub use displacement::derangements_with_max_displacement_by_range;
+pub use displacement::derangements_with_min_displacement;
+pub use displacement::derangements_with_min_displacement_by_range;
+
+pub use shard::Shard;
+
+pub use counting::count_cyclic_permutations;
+pub use counting::count_derangements;
+pub use counting::count_derangements_by_cycle_type;
+pub use counting::count_derangements_by_range_window;
+pub use counting::count_derangements_k;
+pub use counting::count_derangements_with_max_displacement;
+pub use counting::count_derangements_with_min_displacement;
+pub use counting::count_distinct_derangements;
+pub use counting::count_even_derangements;
+pub use counting::count_involutive_derangements;
+pub use counting::count_odd_derangements;
+pub use counting::count_rencontres;
+pub use counting::count_restricted_permutations;
+pub use counting::count_restricted_permutations_by_map_index;
+pub use counting::count_restricted_permutations_by_map_value;
+
+#[cfg(feature = "bigint")]
+pub use counting::{count_derangements_big, count_derangements_k_big, count_rencontres_big};
 
 pub use restricted_permutations::restricted_permutations;
+pub use restricted_permutations::restricted_permutations_by_fn;
 pub use restricted_permutations::restricted_permutations_by_map_index;
 pub use restricted_permutations::restricted_permutations_by_map_value;
+pub use restricted_permutations::restricted_permutations_by_pairs;
+pub use restricted_permutations::restricted_permutations_by_restriction;
 pub use restricted_permutations::restricted_permutations_by_self;
 
+pub use pairs::PairConstraints;
+
+pub use permutation::InvalidPermutation;
+pub use permutation::Permutation;
+
+pub use restriction::perfect_matchings;
+pub use restriction::Restriction;
+
 pub use derangements::derangements;
 pub use derangements::distinct_derangements;
 
+pub use involutions::involutive_derangements;
+pub use involutions::involutive_derangements_by_range;
+
+pub use fixed_points::permutations_with_at_least_fixed_points;
+pub use fixed_points::permutations_with_at_least_fixed_points_by_range;
+pub use fixed_points::permutations_with_at_most_fixed_points;
+pub use fixed_points::permutations_with_at_most_fixed_points_by_range;
+pub use fixed_points::permutations_with_fixed_points;
+pub use fixed_points::permutations_with_fixed_points_by_range;
+
+pub use ranking::derangement_rank;
+pub use ranking::derangement_rank_by_range;
+pub use ranking::derangement_unrank;
+pub use ranking::derangement_unrank_by_range;
+
 pub use fast_permutations::distinct_permutations;
 pub use fast_permutations::fast_permutations;
+
+#[cfg(feature = "rand")]
+pub use random::{
+    derange_in_place, random_cyclic_permutation, random_derangement, random_involutive_derangement,
+    random_restricted_permutation_by_map_index, random_restricted_permutation_by_map_value,
+    RestrictedPermutationSampler,
+};
+
+#[cfg(feature = "rayon")]
+pub use parallel::{
+    par_derangements, par_derangements_by_range, par_restricted_permutations_by_map_index,
+};
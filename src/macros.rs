@@ -0,0 +1,28 @@
+// `clone_fields` and `debug_fmt_fields` are adapted from the `itertools` crate
+// (https://github.com/rust-itertools/itertools), licensed under MIT or Apache-2.0.
+
+/// Implement `clone` by cloning each of the given fields, for iterators over `I::Item` that
+/// should be `Clone` without requiring `I: Clone`.
+macro_rules! clone_fields {
+    ($($field:ident),*) => {
+        #[inline]
+        fn clone(&self) -> Self {
+            Self {
+                $($field: self.$field.clone(),)*
+            }
+        }
+    }
+}
+
+/// Implement `fmt` as a `debug_struct` of the given fields.
+macro_rules! debug_fmt_fields {
+    ($tyname:ident, $($($field:tt).+),*) => {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            f.debug_struct(stringify!($tyname))
+                $(
+                .field(stringify!($($field).+), &self.$($field).+)
+                )*
+                .finish()
+        }
+    }
+}
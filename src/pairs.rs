@@ -0,0 +1,199 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+const WORD: usize = u64::BITS as usize;
+
+/// Constraints between pairs of values of a permutation: values that may not be next to each
+/// other, and values that have to come before others.
+///
+/// Like in a `Restriction`, values are referred to by their index in the input. In a circular
+/// arrangement the first and last position are next to each other as well, but positions are
+/// still numbered, so rotations of an arrangement are different permutations and "before" means
+/// at a lower position.
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{restricted_permutations_by_pairs, PairConstraints};
+///
+/// // Ann and Bob can't sit next to each other, and Cat has to be seated before Ann
+/// let mut pairs = PairConstraints::new();
+/// pairs.forbid_adjacent(0, 1);
+/// pairs.require_before(2, 0);
+/// assert_equal(restricted_permutations_by_pairs(vec!["Ann", "Bob", "Cat"].into_iter(), 3, pairs),
+///     [["Bob", "Cat", "Ann"]]);
+///
+/// // Around a table of four, the first and last seat are next to each other as well
+/// let mut pairs = PairConstraints::circular();
+/// pairs.forbid_adjacent(0, 1);
+/// assert_equal(restricted_permutations_by_pairs(0..4, 4, pairs),
+///     [[0, 2, 1, 3], [0, 3, 1, 2], [1, 2, 0, 3], [1, 3, 0, 2], [2, 0, 3, 1], [2, 1, 3, 0],
+///      [3, 0, 2, 1], [3, 1, 2, 0]]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PairConstraints {
+    apart: BTreeSet<(usize, usize)>,
+    before: BTreeSet<(usize, usize)>,
+    circular: bool,
+}
+
+impl PairConstraints {
+    /// Create constraints for a linear arrangement, where nothing is forbidden yet.
+    pub fn new() -> Self {
+        PairConstraints::default()
+    }
+
+    /// Create constraints for a circular arrangement, where nothing is forbidden yet.
+    pub fn circular() -> Self {
+        PairConstraints {
+            circular: true,
+            ..PairConstraints::default()
+        }
+    }
+
+    /// Whether the first and last position are next to each other.
+    pub fn is_circular(&self) -> bool {
+        self.circular
+    }
+
+    /// Forbid the values with the given indices to be next to each other, in either order.
+    pub fn forbid_adjacent(&mut self, a: usize, b: usize) {
+        self.apart.insert((a.min(b), a.max(b)));
+    }
+
+    /// Require the value with index `first` to come before the value with index `then`, if both
+    /// are in the permutation.
+    pub fn require_before(&mut self, first: usize, then: usize) {
+        self.before.insert((first, then));
+    }
+
+    /// Whether the values with the given indices may be next to each other.
+    pub fn is_adjacent_allowed(&self, a: usize, b: usize) -> bool {
+        !self.apart.contains(&(a.min(b), a.max(b)))
+    }
+
+    /// Whether value `index` can be placed after `prefix` (the indices of which are also given
+    /// as the bitset `used`) in a permutation of `k` positions out of `n` values.
+    pub(crate) fn accepts(
+        &self,
+        prefix: &[usize],
+        used: &[u64],
+        index: usize,
+        n: usize,
+        k: usize,
+    ) -> bool {
+        if let Some(&last) = prefix.last() {
+            let closes_circle = self.circular && k > 2 && prefix.len() == k - 1;
+            if !self.is_adjacent_allowed(last, index)
+                || closes_circle && !self.is_adjacent_allowed(prefix[0], index)
+            {
+                return false;
+            }
+        }
+        let placed = |x: usize| {
+            x == index
+                || used
+                    .get(x / WORD)
+                    .is_some_and(|word| word & (1 << (x % WORD)) != 0)
+        };
+
+        // A value that has to come first can't be placed once the other one is
+        if self
+            .before
+            .range((index, 0)..=(index, usize::MAX))
+            .any(|&(_, then)| then != index && placed(then))
+        {
+            return false;
+        }
+
+        // A value that had to come before a placed one is left out for good, which only works if
+        // enough other values are left to fill the remaining positions
+        let excluded = self
+            .before
+            .iter()
+            .filter(|&&(first, then)| first < n && placed(then) && !placed(first))
+            .map(|&(first, _)| first)
+            .collect::<BTreeSet<_>>();
+        excluded.len() <= n - k
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fast_permutations, restricted_permutations_by_pairs};
+    use itertools::{assert_equal, Itertools};
+
+    #[test]
+    fn test_circular_small() {
+        // With two or three seats around a table, every seat is next to every other one
+        for n in [2, 3] {
+            for (a, b) in (0..n).tuple_combinations() {
+                let mut pairs = PairConstraints::circular();
+                pairs.forbid_adjacent(a, b);
+                assert_eq!(restricted_permutations_by_pairs(0..n, n, pairs).count(), 0);
+            }
+            let pairs = PairConstraints::circular();
+            assert_equal(
+                restricted_permutations_by_pairs(0..n, n, pairs),
+                fast_permutations(0..n, n).sorted(),
+            );
+        }
+
+        // Only the circular arrangement of three makes the first and last seat neighbours
+        let mut pairs = PairConstraints::new();
+        pairs.forbid_adjacent(0, 2);
+        assert_equal(
+            restricted_permutations_by_pairs(0..3, 3, pairs),
+            [[0, 1, 2], [2, 1, 0]],
+        );
+    }
+
+    #[test]
+    fn test_contradictory_before() {
+        for length in [2, 3] {
+            let mut pairs = PairConstraints::new();
+            for i in 0..length {
+                pairs.require_before(i, (i + 1) % length);
+            }
+            assert_eq!(
+                restricted_permutations_by_pairs(0..4, 4, pairs.clone()).count(),
+                0
+            );
+
+            // Without all values, the order only matters for the values that are there
+            for k in 0..=4 {
+                let expected = fast_permutations(0..4, k)
+                    .filter(|x| {
+                        pairs.before.iter().all(|(first, then)| {
+                            let position = |value| x.iter().position(|x| x == value);
+                            match (position(first), position(then)) {
+                                (Some(first), Some(then)) => first < then,
+                                _ => true,
+                            }
+                        })
+                    })
+                    .sorted()
+                    .collect_vec();
+                assert_equal(
+                    restricted_permutations_by_pairs(0..4, k, pairs.clone()),
+                    expected,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_before_prunes_early() {
+        // Placing 1 while 0 is unplaced can only work if 0 is left out
+        let mut pairs = PairConstraints::new();
+        pairs.require_before(0, 1);
+        assert!(!pairs.accepts(&[], &[0], 1, 3, 3));
+        assert!(pairs.accepts(&[], &[0], 1, 3, 2));
+        assert!(pairs.accepts(&[0], &[1], 1, 3, 3));
+        assert!(!pairs.accepts(&[1], &[2], 0, 3, 2));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::count_derangements;
+use crate::derangements_range::derangements_by_range;
+use crate::restriction::{PerfectMatchings, Restriction};
+use rayon::iter::Either;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// How many independent parts to aim for per thread, so that uneven parts still balance out
+const PARTS_PER_THREAD: usize = 8;
+
+fn parts() -> usize {
+    rayon::current_num_threads() * PARTS_PER_THREAD
+}
+
+/// Split the search for the matchings of `restriction` into prefix subtrees, and search each of
+/// them on its own.
+fn par_search<T>(values: Vec<T>, restriction: Restriction) -> impl ParallelIterator<Item = Vec<T>>
+where
+    T: Clone + Send + Sync,
+{
+    let (n, k) = (restriction.values(), restriction.positions());
+
+    // Extend all prefixes one position at a time until there are enough of them to keep every
+    // thread busy; prefixes without any completion simply produce nothing later on
+    let mut prefixes = vec![vec![]];
+    while prefixes.len() < parts() && prefixes.first().is_some_and(|x| x.len() < k.min(n)) {
+        prefixes = prefixes
+            .iter()
+            .flat_map(|prefix: &Vec<usize>| {
+                (0..n)
+                    .filter(|&index| {
+                        !prefix.contains(&index) && restriction.is_allowed(index, prefix.len())
+                    })
+                    .map(move |index| [prefix.as_slice(), &[index]].concat())
+            })
+            .collect();
+    }
+
+    let values = Arc::new(values);
+    prefixes.into_par_iter().flat_map_iter(move |prefix| {
+        let values = Arc::clone(&values);
+        let mut matchings = PerfectMatchings::with_prefix(restriction.clone(), &prefix);
+        std::iter::from_fn(move || {
+            matchings
+                .advance()
+                .map(|indices| indices.iter().map(|&index| values[index].clone()).collect())
+        })
+    })
+}
+
+/// Derange k or all elements of an iterable in parallel.
+///
+/// The derangements are split by their first elements into independent parts, so the result
+/// contains the same derangements as `derangements`, but not in the same order.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to derange
+/// * `k`: `usize` integer that determines how many elements each derangement should have
+///
+/// returns: parallel iterator with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use rayon::prelude::*;
+/// use derangements::par_derangements;
+/// let mut result: Vec<Vec<usize>> = par_derangements(vec![0usize, 1, 2].into_iter(), 3).collect();
+/// result.sort();
+/// assert_eq!(result, [[1, 2, 0], [2, 0, 1]]);
+/// ```
+pub fn par_derangements<I>(iter: I, k: usize) -> impl ParallelIterator<Item = Vec<I::Item>>
+where
+    I: Iterator,
+    I::Item: Clone + Ord + Send + Sync,
+    usize: From<I::Item>,
+{
+    let mut values = Vec::from_iter(iter);
+    values.sort_unstable();
+    let restriction = Restriction::from_indices(&values, k);
+    par_search(values, restriction)
+}
+
+/// Derange the range 0..n in parallel.
+///
+/// The sequence of `derangements_by_range` is split into contiguous blocks that are each
+/// generated on their own, by skipping ahead to the start of the block. The result contains the
+/// same derangements as `derangements_by_range`, but not necessarily in the same order. If the
+/// blocks would be too large to count in a `usize`, the derangements are split by their first
+/// elements instead, as for `par_derangements`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: parallel iterator with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use rayon::prelude::*;
+/// use derangements::par_derangements_by_range;
+/// assert_eq!(par_derangements_by_range(9).count(), 133496);
+/// ```
+pub fn par_derangements_by_range(n: usize) -> impl ParallelIterator<Item = Vec<usize>> {
+    let parts = parts() as u128;
+    let Some(total) =
+        count_derangements(n).filter(|&total| usize::try_from(total.div_ceil(parts)).is_ok())
+    else {
+        let values = (0..n).collect::<Vec<_>>();
+        let restriction = Restriction::from_indices(&values, n);
+        return Either::Right(par_search(values, restriction));
+    };
+    let parts = total.clamp(1, parts);
+    Either::Left((0..parts).into_par_iter().flat_map_iter(move |part| {
+        let mut derangements = derangements_by_range(n);
+        let start = total * part / parts;
+        let end = total * (part + 1) / parts;
+        derangements.skip_ahead(start);
+        derangements.take(usize::try_from(end - start).unwrap())
+    }))
+}
+
+/// Permute k or all elements of an iterable in parallel while excluding based on an input
+/// restriction.
+///
+/// The permutations are split by their first elements into independent parts, so the result
+/// contains the same permutations as `restricted_permutations_by_map_index`, but not in the same
+/// order.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `restrict`: `HashMap<usize, Vec<I::Item>>`, indicating which elements can not be at an index
+///
+/// returns: parallel iterator with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use rayon::prelude::*;
+/// use derangements::par_restricted_permutations_by_map_index;
+///
+/// // Exclude from index 0 the values 0 and 1, and from index 1 the values 1 and 2.
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// let mut result: Vec<Vec<i32>> =
+///     par_restricted_permutations_by_map_index(vec![0, 1, 2, 3].into_iter(), 3, restrict).collect();
+/// result.sort();
+/// assert_eq!(result, [[2, 0, 1], [2, 0, 3], [2, 3, 0], [2, 3, 1], [3, 0, 1], [3, 0, 2]]);
+/// ```
+pub fn par_restricted_permutations_by_map_index<I>(
+    iter: I,
+    k: usize,
+    restrict: HashMap<usize, Vec<I::Item>>,
+) -> impl ParallelIterator<Item = Vec<I::Item>>
+where
+    I: Iterator,
+    I::Item: Clone + Ord + Send + Sync,
+{
+    let values = Vec::from_iter(iter);
+    let restriction = Restriction::from_map_index(&values, k, &restrict);
+    par_search(values, restriction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{derangements, restricted_permutations_by_map_index};
+    use itertools::{assert_equal, Itertools};
+
+    #[test]
+    fn test_par_derangements() {
+        for n in 0..=9 {
+            for k in [0, n / 2, n] {
+                let result = par_derangements(0..n, k).collect::<Vec<_>>();
+                assert_equal(result.into_iter().sorted(), derangements(0..n, k).sorted());
+            }
+        }
+        let values = vec![0usize, 1, 1, 3, 7, 7];
+        let result = par_derangements(values.clone().into_iter(), 5).collect::<Vec<_>>();
+        assert_equal(
+            result.into_iter().sorted(),
+            derangements(values.into_iter(), 5).sorted(),
+        );
+    }
+
+    #[test]
+    fn test_par_derangements_by_range() {
+        for n in 0..=9 {
+            let result = par_derangements_by_range(n).collect::<Vec<_>>();
+            assert_equal(
+                result.into_iter().sorted(),
+                derangements_by_range(n).sorted(),
+            );
+        }
+
+        // Blocks of more than usize::MAX derangements are split by their first elements instead
+        let result = par_derangements_by_range(30)
+            .take_any(100)
+            .collect::<Vec<_>>();
+        assert_eq!(result.len(), 100);
+        assert!(result
+            .iter()
+            .all(|x| x.iter().enumerate().all(|(i, &v)| i != v) && x.iter().all_unique()));
+    }
+
+    #[test]
+    fn test_par_restricted_permutations_by_map_index() {
+        for n in 0..=9 {
+            let restrict: HashMap<usize, Vec<usize>> =
+                HashMap::from_iter((0..n).map(|i| (i, vec![i, (i * 3 + 1) % n, n - 1 - i])));
+            for k in [0, n / 2, n] {
+                let result = par_restricted_permutations_by_map_index(0..n, k, restrict.clone())
+                    .collect::<Vec<_>>();
+                assert_equal(
+                    result.into_iter().sorted(),
+                    restricted_permutations_by_map_index(0..n, k, restrict.clone()).sorted(),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use crate::counting::{alternating_sum, binomial, permutations, Count};
+use crate::restriction::Restriction;
+use std::collections::HashMap;
+
+/// Above this many values that are forbidden somewhere, Ryser's formula has too many terms (at
+/// this many, a dense 22 x 22 restriction takes about a third of a second)
+const RYSER_LIMIT: usize = 22;
+
+/// Above this many reduced boards, counting the rook numbers of a board takes too long
+const ROOK_LIMIT: usize = 1 << 16;
+
+/// Above this many cells, a connected board is reduced with too deep a recursion
+const ROOK_CELLS: usize = 1024;
+
+/// Above this many bits of state, a transfer matrix over the rows of a band takes too long
+pub(crate) const BAND_LIMIT: usize = 14;
+
+/// Number of k-permutations that satisfy a restriction, which is the permanent of its matrix of
+/// allowed (value, position) pairs.
+///
+/// Sparse restrictions (at most two forbidden pairs per value on average) are counted with the
+/// rook numbers of the forbidden pairs, and others with Ryser's formula. If the forbidden pairs
+/// are too entangled for the rook numbers and more than `RYSER_LIMIT` values are restricted, the
+/// restriction is too large to count and this returns `None`.
+pub(crate) fn permanent(restriction: &Restriction) -> Option<u128> {
+    let (n, k) = (restriction.values(), restriction.positions());
+    if k > n {
+        return Some(0);
+    }
+    let forbidden = (0..k)
+        .flat_map(|position| (0..n).map(move |value| (position, value)))
+        .filter(|&(position, value)| !restriction.is_allowed(value, position))
+        .collect::<Vec<_>>();
+    let restricted = (0..n)
+        .filter(|&value| (0..k).any(|position| !restriction.is_allowed(value, position)))
+        .count();
+    if forbidden.len() <= 2 * n || restricted > RYSER_LIMIT {
+        rook_permanent(n, k, &forbidden).or_else(|| match restricted <= RYSER_LIMIT {
+            true => ryser(restriction),
+            false => None,
+        })
+    } else {
+        ryser(restriction)
+    }
+}
+
+/// Ryser's formula for k <= n positions: with c_i(T) the number of values in T that are allowed at
+/// position i, the permanent is sum_{|T| <= k} (-1)^(k - |T|) C(n - |T|, k - |T|) prod_i c_i(T).
+///
+/// The subsets T are visited in Gray code order, so that each one differs from the previous one
+/// by a single value. Values that are allowed everywhere are interchangeable, so only the number
+/// of them in T is varied. All arithmetic wraps modulo 2^128, which is exact because the permanent
+/// is at most n! / (n - k)!; if that bound does not fit in a `u128`, this returns `None`.
+fn ryser(restriction: &Restriction) -> Option<u128> {
+    let (n, k) = (restriction.values(), restriction.positions());
+    permutations::<u128>(n, k)?;
+    let (free, restricted): (Vec<usize>, Vec<usize>) =
+        (0..n).partition(|&value| (0..k).all(|position| restriction.is_allowed(value, position)));
+    let free = free.len();
+    let choose_free = (0..=k)
+        .map(|j| binomial::<u128>(free, j))
+        .collect::<Option<Vec<_>>>()?;
+    let choose_rest = (0..=k)
+        .map(|t| binomial::<u128>(n - t, k - t))
+        .collect::<Option<Vec<_>>>()?;
+
+    let allowed = restricted
+        .iter()
+        .map(|&value| {
+            (0..k)
+                .filter(|&position| restriction.is_allowed(value, position))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut counts = vec![0usize; k];
+    let mut chosen = vec![false; restricted.len()];
+    let mut size = 0;
+    let mut total = 0u128;
+    for step in 0..1u64 << restricted.len() {
+        if step > 0 {
+            let i = step.trailing_zeros() as usize;
+            chosen[i] = !chosen[i];
+            let update = |x: usize| if chosen[i] { x + 1 } else { x - 1 };
+            for &position in &allowed[i] {
+                counts[position] = update(counts[position]);
+            }
+            size = update(size);
+        }
+        if size > k {
+            continue;
+        }
+
+        // Beyond the number of free values, C(free, j) is zero
+        for (j, choose) in choose_free
+            .iter()
+            .enumerate()
+            .take((k - size).min(free) + 1)
+        {
+            let t = size + j;
+            let term = counts
+                .iter()
+                .fold(choose.wrapping_mul(choose_rest[t]), |acc, &c| {
+                    acc.wrapping_mul((c + j) as u128)
+                });
+            if (k - t) % 2 == 0 {
+                total = total.wrapping_add(term);
+            } else {
+                total = total.wrapping_sub(term);
+            }
+        }
+    }
+    Some(total)
+}
+
+/// Count with the rook numbers r_t of the forbidden (position, value) pairs: by inclusion-exclusion
+/// over the positions that do hold a forbidden value, sum_t (-1)^t r_t (n - t)! / (n - k)!.
+fn rook_permanent(n: usize, k: usize, forbidden: &[(usize, usize)]) -> Option<u128> {
+    let r = rook_numbers::<u128>(forbidden)?;
+    alternating_sum(
+        r.iter()
+            .enumerate()
+            .map(|(t, r_t)| r_t.mul_checked(&permutations(n - t, k - t)?)),
+    )
+}
+
+/// Rook numbers r_0, r_1, ... of a board: the number of ways to put t rooks on its cells such that
+/// no two share a row or a column.
+///
+/// The board is first split into parts that share no rows or columns, of which the rook
+/// polynomials multiply. A connected board is reduced with a cell c as
+/// R(board) = R(board without c) + x R(board without the row and column of c), remembering the
+/// boards that were already reduced (such as the shorter pieces of a long band).
+///
+/// Returns `None` if a result does not fit, or if the board is too large to reduce: a connected
+/// part of more than `ROOK_CELLS` cells, or more than `ROOK_LIMIT` different boards to reduce.
+pub(crate) fn rook_numbers<C: Count>(cells: &[(usize, usize)]) -> Option<Vec<C>> {
+    rook_numbers_memo(cells, &mut HashMap::new())
+}
+
+type Board = Vec<(usize, usize)>;
+
+fn rook_numbers_memo<C: Count>(
+    cells: &[(usize, usize)],
+    memo: &mut HashMap<Board, Vec<C>>,
+) -> Option<Vec<C>> {
+    if let Some(result) = memo.get(cells) {
+        return Some(result.clone());
+    }
+    if memo.len() >= ROOK_LIMIT {
+        return None;
+    }
+    let parts = components(cells);
+    let result = if parts.len() > 1 {
+        parts.iter().try_fold(vec![C::from_usize(1)], |acc, part| {
+            multiply(&acc, &rook_numbers_memo(part, memo)?)
+        })?
+    } else if cells.len() > ROOK_CELLS {
+        return None;
+    } else if let Some(&(row, column)) = cells.first() {
+        let mut result = rook_numbers_memo::<C>(&cells[1..], memo)?;
+        let rest = cells
+            .iter()
+            .filter(|&&(r, c)| r != row && c != column)
+            .copied()
+            .collect::<Vec<_>>();
+        for (t, r_t) in rook_numbers_memo::<C>(&rest, memo)?.into_iter().enumerate() {
+            if t + 1 == result.len() {
+                result.push(C::from_usize(0));
+            }
+            result[t + 1] = result[t + 1].add_checked(&r_t)?;
+        }
+        result
+    } else {
+        vec![C::from_usize(1)]
+    };
+    memo.insert(cells.to_vec(), result.clone());
+    Some(result)
+}
+
+/// Rook numbers of the band where row p has the cells in columns p - w + 1 up to p, which wrap
+/// around modulo n if `circular` (requiring n >= 2w) and are cut off at column 0 otherwise.
+///
+/// The rows are added one at a time, keeping the number of placements for each state of which
+/// columns in the window of the next row are used already. With wrapping, this is repeated for
+/// each subset of the last w - 1 columns that the first rows use, which then has to be exactly
+/// that subset, and which the last rows find in use.
+///
+/// Returns `None` if a result does not fit, or if the w bits of a state (and the w - 1 of a
+/// subset that wraps) are more than `BAND_LIMIT`.
+pub(crate) fn band_rook_numbers<C: Count>(n: usize, w: usize, circular: bool) -> Option<Vec<C>> {
+    assert!(w >= 1 && (!circular || n >= 2 * w));
+    if w + if circular { w - 1 } else { 0 } > BAND_LIMIT {
+        return None;
+    }
+    let full = (1 << w) - 1;
+    let mut total = vec![C::from_usize(0); n + 1];
+    let wraps = if circular { 1 << (w - 1) } else { 1 };
+    for wrap in 0..wraps {
+        // Bit j of a state is whether column p - j is used after row p, and bit i - 1 of `wrap`
+        // whether column -i (so n - i) is used by the first rows; the others before 0 are blocked
+        let mut states: Vec<Option<Vec<C>>> = vec![None; 1 << w];
+        let mut start = vec![C::from_usize(0); n + 1];
+        start[0] = C::from_usize(1);
+        states[full & !wrap] = Some(start);
+
+        for p in 0..n {
+            let mut next: Vec<Option<Vec<C>>> = vec![None; 1 << w];
+            for (mask, counts) in states.iter().enumerate() {
+                let Some(counts) = counts else { continue };
+                if p < w && wrap & (1 << (w - 1 - p)) != 0 && mask & (1 << (w - 1)) == 0 {
+                    continue;
+                }
+                let mut shifted = (mask << 1) & full;
+                if circular && p + w > n && wrap & (1 << (n - p - 1)) != 0 {
+                    shifted |= 1;
+                }
+                add_to(&mut next[shifted], counts, 0)?;
+                for j in (0..w).filter(|j| shifted & (1 << j) == 0) {
+                    add_to(&mut next[shifted | (1 << j)], counts, 1)?;
+                }
+            }
+            states = next;
+        }
+
+        for counts in states.iter().flatten() {
+            for (t, r_t) in counts.iter().enumerate() {
+                total[t] = total[t].add_checked(r_t)?;
+            }
+        }
+    }
+    Some(total)
+}
+
+/// Rook numbers of the band around the diagonal of an n x n board, where row p has the cells in
+/// columns p - h up to p + h (as far as they are on the board).
+///
+/// This is the same transfer matrix as for `band_rook_numbers`, with the window of row p running
+/// from column p - h to p + h. Returns `None` if a result does not fit, or if the width 2h + 1 is
+/// above `BAND_LIMIT`.
+pub(crate) fn diagonal_band_rook_numbers<C: Count>(n: usize, h: usize) -> Option<Vec<C>> {
+    let width = h.checked_mul(2)? + 1;
+    if width > BAND_LIMIT {
+        return None;
+    }
+
+    // Bit j of a state is whether column p - h + j is used, before row p
+    let mut states: Vec<Option<Vec<C>>> = vec![None; 1 << width];
+    let mut start = vec![C::from_usize(0); n + 1];
+    start[0] = C::from_usize(1);
+    states[0] = Some(start);
+    for p in 0..n {
+        let mut next: Vec<Option<Vec<C>>> = vec![None; 1 << width];
+        for (mask, counts) in states.iter().enumerate() {
+            let Some(counts) = counts else { continue };
+            add_to(&mut next[mask >> 1], counts, 0)?;
+            for j in (0..width).filter(|&j| mask & (1 << j) == 0 && p + j >= h && p + j < n + h) {
+                add_to(&mut next[(mask | (1 << j)) >> 1], counts, 1)?;
+            }
+        }
+        states = next;
+    }
+
+    let mut total = vec![C::from_usize(0); n + 1];
+    for counts in states.iter().flatten() {
+        for (t, r_t) in counts.iter().enumerate() {
+            total[t] = total[t].add_checked(r_t)?;
+        }
+    }
+    Some(total)
+}
+
+/// Add `counts`, moved up by `shift` rooks, to a state
+fn add_to<C: Count>(state: &mut Option<Vec<C>>, counts: &[C], shift: usize) -> Option<()> {
+    let state = state.get_or_insert_with(|| vec![C::from_usize(0); counts.len()]);
+    for t in shift..counts.len() {
+        state[t] = state[t].add_checked(&counts[t - shift])?;
+    }
+    Some(())
+}
+
+/// Split a board into the parts that are connected by sharing a row or a column
+fn components(cells: &[(usize, usize)]) -> Vec<Vec<(usize, usize)>> {
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut parent = (0..cells.len()).collect::<Vec<_>>();
+    let (mut rows, mut columns) = (HashMap::new(), HashMap::new());
+    for (i, &(row, column)) in cells.iter().enumerate() {
+        for first in [
+            *rows.entry(row).or_insert(i),
+            *columns.entry(column).or_insert(i),
+        ] {
+            let (a, b) = (find(&mut parent, first), find(&mut parent, i));
+            parent[a] = b;
+        }
+    }
+
+    let mut parts: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (i, &cell) in cells.iter().enumerate() {
+        parts.entry(find(&mut parent, i)).or_default().push(cell);
+    }
+    parts.into_values().collect()
+}
+
+/// Product of two polynomials given by their coefficients
+fn multiply<C: Count>(a: &[C], b: &[C]) -> Option<Vec<C>> {
+    let mut product = vec![C::from_usize(0); a.len() + b.len() - 1];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            product[i + j] = product[i + j].add_checked(&x.mul_checked(y)?)?;
+        }
+    }
+    Some(product)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perfect_matchings;
+
+    fn pseudo_random(n: usize, k: usize, density: u64, seed: &mut u64) -> Restriction {
+        let mut restriction = Restriction::new(n, k);
+        for value in 0..n {
+            for position in 0..k {
+                *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                if (*seed >> 60) < density {
+                    restriction.forbid(value, position);
+                }
+            }
+        }
+        restriction
+    }
+
+    #[test]
+    fn test_permanent_methods() {
+        // Both methods against enumeration, for sparse and dense restrictions alike
+        let mut seed = 42u64;
+        for n in 0..=7 {
+            for k in 0..=n + 1 {
+                for density in [0, 1, 3, 8, 12] {
+                    let restriction = pseudo_random(n, k, density, &mut seed);
+                    let expected = perfect_matchings(restriction.clone()).count() as u128;
+                    assert_eq!(permanent(&restriction), Some(expected));
+                    if k <= n {
+                        let forbidden = (0..k)
+                            .flat_map(|position| (0..n).map(move |value| (position, value)))
+                            .filter(|&(position, value)| !restriction.is_allowed(value, position))
+                            .collect::<Vec<_>>();
+                        assert_eq!(ryser(&restriction), Some(expected));
+                        assert_eq!(rook_permanent(n, k, &forbidden), Some(expected));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rook_numbers() {
+        // A full 3x3 board has r_t = C(3, t)^2 t!, and a diagonal one r_t = C(n, t)
+        let full = (0..3)
+            .flat_map(|r| (0..3).map(move |c| (r, c)))
+            .collect::<Vec<_>>();
+        assert_eq!(rook_numbers::<u128>(&full), Some(vec![1, 9, 18, 6]));
+        let diagonal = (0..5).map(|i| (i, i)).collect::<Vec<_>>();
+        assert_eq!(
+            rook_numbers::<u128>(&diagonal),
+            Some(vec![1, 5, 10, 10, 5, 1])
+        );
+    }
+
+    #[test]
+    fn test_band_rook_numbers() {
+        for n in 0..=12 {
+            for w in 1..=4 {
+                for circular in [false, true] {
+                    if circular && n < 2 * w {
+                        continue;
+                    }
+                    let cells = (0..n)
+                        .flat_map(|row| (0..w).map(move |j| (row, j)))
+                        .filter(|&(row, j)| circular || row >= j)
+                        .map(|(row, j)| (row, (row + n - j) % n))
+                        .collect::<Vec<_>>();
+                    assert_eq!(
+                        band_rook_numbers::<u128>(n, w, circular),
+                        rook_numbers::<u128>(&cells).map(|mut r| {
+                            r.resize(n + 1, 0);
+                            r
+                        })
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_permanent_large() {
+        // Derangements of 30 values, and the ménage numbers for a band of two forbidden values
+        // at each position
+        let mut restriction = Restriction::new(30, 30);
+        for i in 0..30 {
+            restriction.forbid(i, i);
+        }
+        assert_eq!(permanent(&restriction), crate::count_derangements(30));
+
+        let mut restriction = Restriction::new(10, 10);
+        for i in 0..10 {
+            restriction.forbid(i, i);
+            restriction.forbid((i + 1) % 10, i);
+        }
+        assert_eq!(ryser(&restriction), Some(439792));
+        assert_eq!(permanent(&restriction), Some(439792));
+    }
+
+    #[test]
+    fn test_permanent_too_large() {
+        // Too many restricted values for Ryser's formula, and too entangled for rook numbers
+        let mut seed = 42u64;
+        let restriction = pseudo_random(40, 40, 6, &mut seed);
+        assert_eq!(permanent(&restriction), None);
+
+        // A band that is connected all around, which would recurse too deep to reduce
+        let mut restriction = Restriction::new(2000, 2000);
+        for i in 0..2000 {
+            restriction.forbid(i, i);
+            restriction.forbid((i + 1) % 2000, i);
+        }
+        assert_eq!(permanent(&restriction), None);
+    }
+}
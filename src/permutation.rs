@@ -0,0 +1,376 @@
+use crate::derangements_range::{derangements_by_range, DerangementsRangeIterator};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::iter::FusedIterator;
+
+/// A permutation of 0..n, which maps i to `self[i]`, like the derangements of
+/// `derangements_by_range` that put value `x[i]` at position i.
+///
+/// It is created from a `Vec<usize>` with `try_from`, which checks that every value of 0..n occurs
+/// exactly once, and converts back into one with `into`.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::Permutation;
+///
+/// let p = Permutation::try_from(vec![1, 2, 0, 4, 3]).unwrap();
+/// assert_eq!(p.cycles(), [vec![0, 1, 2], vec![3, 4]]);
+/// assert_eq!(p.cycle_type(), [3, 2]);
+/// assert_eq!((p.sign(), p.order()), (-1, Some(6)));
+/// assert!(p.is_derangement());
+/// assert_eq!(Vec::from(p.inverse()), [2, 0, 1, 4, 3]);
+///
+/// assert!(Permutation::try_from(vec![1, 1, 0]).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "Vec<usize>", into = "Vec<usize>"))]
+pub struct Permutation {
+    images: Vec<usize>,
+}
+
+/// The error for a `Vec<usize>` that is not a permutation of 0..n, where n is its length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InvalidPermutation;
+
+impl Display for InvalidPermutation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("not a permutation of 0..n, with n its length")
+    }
+}
+
+impl Error for InvalidPermutation {}
+
+impl Permutation {
+    /// The permutation of 0..n that maps every element to itself.
+    pub fn identity(n: usize) -> Self {
+        Permutation {
+            images: (0..n).collect(),
+        }
+    }
+
+    /// For vectors that are known to be permutations, such as the output of the generators
+    pub(crate) fn from_vec_unchecked(images: Vec<usize>) -> Self {
+        debug_assert!(Permutation::try_from(images.clone()).is_ok());
+        Permutation { images }
+    }
+
+    /// The number of elements n.
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    /// Whether this is the permutation of no elements.
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// The images of 0..n.
+    pub fn as_slice(&self) -> &[usize] {
+        &self.images
+    }
+
+    /// The permutation that maps `self[i]` back to i.
+    pub fn inverse(&self) -> Self {
+        let mut images = vec![0; self.len()];
+        for (i, &x) in self.images.iter().enumerate() {
+            images[x] = i;
+        }
+        Permutation { images }
+    }
+
+    /// The permutation that first applies `other` and then `self`, so it maps i to
+    /// `self[other[i]]`.
+    ///
+    /// # Panics
+    ///
+    /// If the permutations don't have the same length.
+    pub fn compose(&self, other: &Permutation) -> Self {
+        assert_eq!(self.len(), other.len(), "permutations of different lengths");
+        Permutation {
+            images: other.images.iter().map(|&i| self.images[i]).collect(),
+        }
+    }
+
+    /// The cycles, including fixed points as cycles of one element. Each cycle starts at its
+    /// smallest element and then follows the permutation, and the cycles are sorted by their
+    /// first element.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut seen = vec![false; self.len()];
+        let mut cycles = vec![];
+        for start in 0..self.len() {
+            let mut cycle = vec![];
+            let mut i = start;
+            while !seen[i] {
+                seen[i] = true;
+                cycle.push(i);
+                i = self.images[i];
+            }
+            if !cycle.is_empty() {
+                cycles.push(cycle);
+            }
+        }
+        cycles
+    }
+
+    /// The lengths of the cycles from longest to shortest, a partition of n as used by
+    /// `CycleSpec::Partition`.
+    pub fn cycle_type(&self) -> Vec<usize> {
+        let mut lengths = self.cycles().iter().map(Vec::len).collect::<Vec<_>>();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths
+    }
+
+    /// The elements that are mapped to themselves, in increasing order.
+    pub fn fixed_points(&self) -> Vec<usize> {
+        (0..self.len()).filter(|&i| self.images[i] == i).collect()
+    }
+
+    /// Whether no element is mapped to itself.
+    pub fn is_derangement(&self) -> bool {
+        self.images.iter().enumerate().all(|(i, &x)| i != x)
+    }
+
+    /// The sign: 1 for an even number of transpositions and -1 for an odd number, which is
+    /// (-1)^(n - number of cycles).
+    pub fn sign(&self) -> i8 {
+        if (self.len() - self.cycles().len()).is_multiple_of(2) {
+            1
+        } else {
+            -1
+        }
+    }
+
+    /// The number of times the permutation has to be applied to get back the identity: the least
+    /// common multiple of the cycle lengths, or `None` if it does not fit in a `u128`.
+    pub fn order(&self) -> Option<u128> {
+        fn gcd(a: u128, b: u128) -> u128 {
+            if b == 0 {
+                a
+            } else {
+                gcd(b, a % b)
+            }
+        }
+
+        self.cycle_type()
+            .into_iter()
+            .try_fold(1u128, |acc, length| {
+                let length = length as u128;
+                (acc / gcd(acc, length)).checked_mul(length)
+            })
+    }
+
+    /// Rearrange a slice in place, such that afterwards position i holds the item that was at
+    /// position `self[i]`, as the iterables are permuted by the generators.
+    ///
+    /// # Panics
+    ///
+    /// If the slice does not have the same length as the permutation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use derangements::Permutation;
+    /// let mut words = ["a", "b", "c", "d"];
+    /// Permutation::try_from(vec![2, 0, 1, 3]).unwrap().apply(&mut words);
+    /// assert_eq!(words, ["c", "a", "b", "d"]);
+    /// ```
+    pub fn apply<T>(&self, slice: &mut [T]) {
+        assert_eq!(slice.len(), self.len(), "slice of a different length");
+        for cycle in self.cycles() {
+            for pair in cycle.windows(2) {
+                slice.swap(pair[0], pair[1]);
+            }
+        }
+    }
+}
+
+impl TryFrom<Vec<usize>> for Permutation {
+    type Error = InvalidPermutation;
+
+    fn try_from(images: Vec<usize>) -> Result<Self, Self::Error> {
+        let mut seen = vec![false; images.len()];
+        for &x in &images {
+            match seen.get_mut(x) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(InvalidPermutation),
+            }
+        }
+        Ok(Permutation { images })
+    }
+}
+
+impl From<Permutation> for Vec<usize> {
+    fn from(permutation: Permutation) -> Self {
+        permutation.images
+    }
+}
+
+impl AsRef<[usize]> for Permutation {
+    fn as_ref(&self) -> &[usize] {
+        &self.images
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct DerangementsRangePermIterator {
+    derangements: DerangementsRangeIterator,
+}
+
+/// Derange all elements of a range of 0 to n (non-inclusive), as `Permutation`s.
+///
+/// These are the derangements of `derangements_by_range`, in the same order.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+///
+/// returns: iterable with the derangements
+///
+/// # Examples
+///
+/// ```
+/// use derangements::derangements_by_range_perm;
+/// let even = derangements_by_range_perm(5).filter(|x| x.sign() == 1).count();
+/// assert_eq!(even, 24);
+/// ```
+pub fn derangements_by_range_perm(n: usize) -> DerangementsRangePermIterator {
+    DerangementsRangePermIterator {
+        derangements: derangements_by_range(n),
+    }
+}
+
+impl Iterator for DerangementsRangePermIterator {
+    type Item = Permutation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.derangements
+            .next()
+            .map(Permutation::from_vec_unchecked)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.derangements.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.derangements
+            .nth(n)
+            .map(Permutation::from_vec_unchecked)
+    }
+}
+
+impl DerangementsRangePermIterator {
+    /// The exact number of derangements that are left, or `None` if it does not fit in a `u128`
+    pub fn remaining(&self) -> Option<u128> {
+        self.derangements.remaining()
+    }
+}
+
+impl FusedIterator for DerangementsRangePermIterator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{derangements_by_range, fast_permutations};
+    use itertools::{assert_equal, Itertools};
+
+    #[test]
+    fn test_try_from() {
+        assert!(Permutation::try_from(vec![]).is_ok());
+        assert!(Permutation::try_from(vec![0, 2, 1]).is_ok());
+        assert_eq!(
+            Permutation::try_from(vec![0, 3, 1]),
+            Err(InvalidPermutation)
+        );
+        assert_eq!(
+            Permutation::try_from(vec![2, 0, 2]),
+            Err(InvalidPermutation)
+        );
+        let p = Permutation::try_from(vec![3, 0, 2, 1]).unwrap();
+        assert_eq!(p.as_slice(), [3, 0, 2, 1]);
+        assert_eq!(Vec::from(p), [3, 0, 2, 1]);
+    }
+
+    #[test]
+    fn test_properties_brute_force() {
+        for n in 0..=6 {
+            let all = fast_permutations(0..n, n)
+                .map(|x| Permutation::try_from(x).unwrap())
+                .collect_vec();
+            for p in &all {
+                let inversions = (0..n)
+                    .tuple_combinations()
+                    .filter(|&(i, j)| p.as_slice()[i] > p.as_slice()[j])
+                    .count();
+                assert_eq!(p.sign(), if inversions % 2 == 0 { 1 } else { -1 });
+                assert_eq!(p.compose(&p.inverse()), Permutation::identity(n));
+                assert_eq!(p.cycle_type().iter().sum::<usize>(), n);
+                assert_eq!(p.is_derangement(), p.fixed_points().is_empty());
+
+                // Applying the permutation order times gives the identity, and no fewer times
+                let order = p.order().unwrap() as usize;
+                let mut power = Permutation::identity(n);
+                for k in 1..=order {
+                    power = power.compose(p);
+                    assert_eq!(power == Permutation::identity(n), k == order);
+                }
+
+                let mut values = (10..10 + n).collect_vec();
+                p.apply(&mut values);
+                assert_equal(values, p.as_slice().iter().map(|&i| 10 + i));
+            }
+            for (p, q) in all.iter().cartesian_product(&all).step_by(7) {
+                assert_eq!(p.compose(q).sign(), p.sign() * q.sign());
+                assert_eq!(p.compose(q).inverse(), q.inverse().compose(&p.inverse()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_derangements_by_range_perm() {
+        for n in 0..=7 {
+            let iter = derangements_by_range_perm(n);
+            assert_eq!(iter.remaining(), derangements_by_range(n).remaining());
+            assert_equal(iter.map(Vec::from), derangements_by_range(n));
+        }
+        assert_eq!(
+            derangements_by_range_perm(8).nth(1000).map(Vec::from),
+            derangements_by_range(8).nth(1000)
+        );
+    }
+
+    #[test]
+    fn test_order_overflow() {
+        // Cycles of the first 40 primes have an order of about 1.7e69
+        let mut images = vec![];
+        for length in (2..).filter(|&x| (2..x).all(|d| x % d != 0)).take(40) {
+            let start = images.len();
+            images.extend((start + 1..start + length).chain([start]));
+        }
+        let p = Permutation::try_from(images).unwrap();
+        assert_eq!(p.order(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let p = Permutation::try_from(vec![2, 0, 1]).unwrap();
+        let json = serde_json::to_string(&p).unwrap();
+        assert_eq!(json, "[2,0,1]");
+        assert_eq!(serde_json::from_str::<Permutation>(&json).unwrap(), p);
+        assert!(serde_json::from_str::<Permutation>("[2,0,2]").is_err());
+
+        let mut iter = derangements_by_range_perm(7);
+        iter.nth(100);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: DerangementsRangePermIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+}
@@ -0,0 +1,716 @@
+use crate::restriction::Restriction;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Up to this many values, restricted permutations are sampled exactly; beyond it a Markov chain
+/// is used.
+const EXACT_SAMPLING_LIMIT: usize = 20;
+
+/// Draw a uniformly random derangement of a range of 0 to n (non-inclusive).
+///
+/// This uses the algorithm of Martínez, Panholzer and Prodinger (2008), which builds the
+/// derangement with swaps like a Fisher-Yates shuffle, instead of rejecting random permutations
+/// that have a fixed point.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `rng`: the random number generator to draw from
+///
+/// returns: a derangement, or `None` if n is 1 and no derangement exists
+///
+/// # Examples
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::random_derangement;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let derangement = random_derangement(10, &mut rng).unwrap();
+/// assert!(derangement.iter().enumerate().all(|(i, &x)| i != x));
+/// assert_eq!(random_derangement(1, &mut rng), None);
+/// ```
+pub fn random_derangement<R>(n: usize, rng: &mut R) -> Option<Vec<usize>>
+where
+    R: Rng + ?Sized,
+{
+    let mut derangement = (0..n).collect::<Vec<_>>();
+    derange_in_place(&mut derangement, rng).then_some(derangement)
+}
+
+/// Shuffle a slice in place such that no element stays at its position, uniformly over all such
+/// shuffles.
+///
+/// Note that this deranges positions: unlike `derangements`, the values themselves are not used
+/// as indices, so the slice can contain items of any type.
+///
+/// # Arguments
+///
+/// * `slice`: the slice to derange
+/// * `rng`: the random number generator to draw from
+///
+/// returns: `false` if the slice has a single element and can't be deranged (it is then left
+/// unchanged), and `true` otherwise
+///
+/// # Examples
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::derange_in_place;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let mut words = ["I", "like", "derangements"];
+/// assert!(derange_in_place(&mut words, &mut rng));
+/// assert!(words == ["like", "derangements", "I"] || words == ["derangements", "I", "like"]);
+/// ```
+pub fn derange_in_place<T, R>(slice: &mut [T], rng: &mut R) -> bool
+where
+    R: Rng + ?Sized,
+{
+    let n = slice.len();
+    if n == 1 {
+        return false;
+    }
+
+    // The probability to close a cycle with u elements left is (u - 1) D(u - 2) / D(u), which is
+    // d(u - 2) / (u d(u)) in terms of d(u) = D(u) / u!, the alternating series for 1/e.
+    let mut ratio = vec![1.0; n + 1];
+    let mut term = 1.0;
+    for u in 1..=n {
+        term /= -(u as f64);
+        ratio[u] = ratio[u - 1] + term;
+    }
+
+    let mut marked = vec![false; n];
+    let mut unmarked = n;
+    let mut i = n;
+    while unmarked >= 2 {
+        i -= 1;
+        if marked[i] {
+            continue;
+        }
+        let j = loop {
+            let j = rng.random_range(0..i);
+            if !marked[j] {
+                break j;
+            }
+        };
+        slice.swap(i, j);
+        let close = ratio[unmarked - 2] / (unmarked as f64 * ratio[unmarked]);
+        if rng.random::<f64>() < close {
+            marked[j] = true;
+            unmarked -= 1;
+        }
+        unmarked -= 1;
+    }
+    true
+}
+
+/// Draw a uniformly random involution of a range of 0 to n (non-inclusive) without fixed points,
+/// which pairs up all elements.
+///
+/// The range is shuffled and then split into consecutive pairs. Each matching comes from the same
+/// number of shuffles, (n / 2)! 2^(n / 2), so all of them are equally likely.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to pair up
+/// * `rng`: the random number generator to draw from
+///
+/// returns: a derangement that is its own inverse, or `None` if n is odd and none exists
+///
+/// # Examples
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::random_involutive_derangement;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let x = random_involutive_derangement(10, &mut rng).unwrap();
+/// assert!((0..10).all(|i| x[i] != i && x[x[i]] == i));
+/// assert_eq!(random_involutive_derangement(5, &mut rng), None);
+/// ```
+pub fn random_involutive_derangement<R>(n: usize, rng: &mut R) -> Option<Vec<usize>>
+where
+    R: Rng + ?Sized,
+{
+    if n % 2 == 1 {
+        return None;
+    }
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.shuffle(rng);
+    let mut involution = vec![0; n];
+    for pair in order.chunks_exact(2) {
+        involution[pair[0]] = pair[1];
+        involution[pair[1]] = pair[0];
+    }
+    Some(involution)
+}
+
+/// Draw a uniformly random permutation of a range of 0 to n (non-inclusive) that is a single cycle.
+///
+/// This uses Sattolo's algorithm: a Fisher-Yates shuffle where each element is swapped with one
+/// of the elements before it, never with itself, which gives each of the (n - 1)! cycles with the
+/// same probability.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `rng`: the random number generator to draw from
+///
+/// returns: a cyclic permutation, or `None` if n < 2 and none exists
+///
+/// # Examples
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::random_cyclic_permutation;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let x = random_cyclic_permutation(10, &mut rng).unwrap();
+/// assert_eq!(std::iter::successors(Some(0), |&i| Some(x[i])).skip(1).position(|i| i == 0), Some(9));
+/// assert_eq!(random_cyclic_permutation(1, &mut rng), None);
+/// ```
+pub fn random_cyclic_permutation<R>(n: usize, rng: &mut R) -> Option<Vec<usize>>
+where
+    R: Rng + ?Sized,
+{
+    if n < 2 {
+        return None;
+    }
+    let mut cycle = (0..n).collect::<Vec<_>>();
+    for i in (1..n).rev() {
+        let j = rng.random_range(0..i);
+        cycle.swap(i, j);
+    }
+    Some(cycle)
+}
+
+/// Draw a uniformly random k-permutation of an iterable, excluding values at indices as in
+/// `restricted_permutations_by_map_index`.
+///
+/// For up to 20 input values, the permutation is sampled exactly: each next element is drawn with
+/// a probability proportional to the number of valid ways to complete the permutation (the
+/// permanent of the remaining allowed matrix). For more values, it is sampled approximately with
+/// a Markov chain that starts at a valid permutation (found by bipartite matching) and then
+/// repeatedly proposes to swap the elements at two random indices, accepting if the result is
+/// still valid. The chain runs for 50 n log2(n) steps. Its stationary distribution is uniform, but
+/// only over the valid permutations that can be reached from the start by such swaps.
+///
+/// The exact sampler first counts the completions of every subset of the values, which takes 2^n
+/// entries (8 MB at n = 20) and about n 2^n steps. To draw more than one permutation, build a
+/// `RestrictedPermutationSampler` once instead, which keeps these counts between draws.
+///
+/// Pass a seeded random number generator to make the result reproducible.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements the permutation should have
+/// * `restrict`: `HashMap<usize, Vec<I::Item>>`, indicating which elements can not be at an index
+/// * `rng`: the random number generator to draw from
+///
+/// returns: a valid permutation, or `None` if there is none
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::random_restricted_permutation_by_map_index;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// let x = random_restricted_permutation_by_map_index(0..4, 3, &restrict, &mut rng).unwrap();
+/// assert!(![0, 1].contains(&x[0]) && ![1, 2].contains(&x[1]));
+///
+/// let restrict = HashMap::from([(0, vec![0, 1, 2, 3])]);
+/// assert_eq!(random_restricted_permutation_by_map_index(0..4, 3, &restrict, &mut rng), None);
+/// ```
+pub fn random_restricted_permutation_by_map_index<I, R>(
+    iter: I,
+    k: usize,
+    restrict: &HashMap<usize, Vec<I::Item>>,
+    rng: &mut R,
+) -> Option<Vec<I::Item>>
+where
+    I: Iterator,
+    I::Item: Clone + Ord,
+    R: Rng + ?Sized,
+{
+    RestrictedPermutationSampler::from_map_index(iter, k, restrict).sample(rng)
+}
+
+/// Draw a uniformly random k-permutation of an iterable, excluding values at indices as in
+/// `restricted_permutations_by_map_value`.
+///
+/// This samples in the same way as `random_restricted_permutation_by_map_index`: exactly for up to
+/// 20 input values, and with a Markov chain otherwise. To draw more than one permutation, use a
+/// `RestrictedPermutationSampler`.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements the permutation should have
+/// * `restrict`: `HashMap<I::Item, Vec<usize>>`, indicating at which indices an element can't be
+/// * `rng`: the random number generator to draw from
+///
+/// returns: a valid permutation, or `None` if there is none
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::random_restricted_permutation_by_map_value;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// let x = random_restricted_permutation_by_map_value(0..4, 3, &restrict, &mut rng).unwrap();
+/// assert!(x[0] != 0 && x[1] != 0 && x[1] != 1 && x[2] != 1);
+/// ```
+pub fn random_restricted_permutation_by_map_value<I, R>(
+    iter: I,
+    k: usize,
+    restrict: &HashMap<I::Item, Vec<usize>>,
+    rng: &mut R,
+) -> Option<Vec<I::Item>>
+where
+    I: Iterator,
+    I::Item: Clone + Ord + Hash,
+    R: Rng + ?Sized,
+{
+    RestrictedPermutationSampler::from_map_value(iter, k, restrict).sample(rng)
+}
+
+/// Draws random restricted k-permutations of the values of an iterable, keeping what only
+/// depends on the restriction between draws.
+///
+/// This samples as `random_restricted_permutation_by_map_index` does, but the counts of the exact
+/// sampler (or the starting permutation of the Markov chain) are only computed once, when the
+/// sampler is built.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use derangements::RestrictedPermutationSampler;
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+/// let sampler = RestrictedPermutationSampler::from_map_index(0..4, 3, &restrict);
+/// for _ in 0..10 {
+///     let x = sampler.sample(&mut rng).unwrap();
+///     assert!(![0, 1].contains(&x[0]) && ![1, 2].contains(&x[1]));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RestrictedPermutationSampler<T> {
+    values: Vec<T>,
+    allowed: Vec<Vec<bool>>,
+    method: Sampling,
+}
+
+#[derive(Debug, Clone)]
+enum Sampling {
+    /// The number of completions for each set of used values
+    Exact(Vec<u64>),
+    /// The valid permutation that the Markov chain starts from
+    Chain(Vec<usize>),
+    Unsatisfiable,
+}
+
+impl<T: Clone + Ord> RestrictedPermutationSampler<T> {
+    /// Build a sampler for the restriction of `restricted_permutations_by_map_index`.
+    ///
+    /// # Arguments
+    ///
+    /// * `iterable`: the iterable of items to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `restrict`: `HashMap<usize, Vec<T>>`, indicating which elements can not be at an index
+    ///
+    /// returns: the sampler
+    pub fn from_map_index<I>(iter: I, k: usize, restrict: &HashMap<usize, Vec<T>>) -> Self
+    where
+        I: Iterator<Item = T>,
+    {
+        let mut values = Vec::from_iter(iter);
+        values.sort_unstable();
+        let restriction = Restriction::from_map_index(&values, k, restrict);
+        Self::new(values, restriction)
+    }
+
+    /// Build a sampler for the restriction of `restricted_permutations_by_map_value`.
+    ///
+    /// # Arguments
+    ///
+    /// * `iterable`: the iterable of items to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `restrict`: `HashMap<T, Vec<usize>>`, indicating at which indices an element can't be
+    ///
+    /// returns: the sampler
+    pub fn from_map_value<I>(iter: I, k: usize, restrict: &HashMap<T, Vec<usize>>) -> Self
+    where
+        I: Iterator<Item = T>,
+        T: Hash,
+    {
+        let mut values = Vec::from_iter(iter);
+        values.sort_unstable();
+        let restriction = Restriction::from_map_value(&values, k, restrict);
+        Self::new(values, restriction)
+    }
+
+    fn new(values: Vec<T>, restriction: Restriction) -> Self {
+        let n = values.len();
+        let allowed = restriction.rows();
+        let method = if allowed.len() > n {
+            Sampling::Unsatisfiable
+        } else if n <= EXACT_SAMPLING_LIMIT {
+            let completions = count_completions(&allowed, n);
+            if completions[0] == 0 {
+                Sampling::Unsatisfiable
+            } else {
+                Sampling::Exact(completions)
+            }
+        } else {
+            restriction
+                .matching()
+                .map_or(Sampling::Unsatisfiable, Sampling::Chain)
+        };
+        RestrictedPermutationSampler {
+            values,
+            allowed,
+            method,
+        }
+    }
+
+    /// Draw a random permutation.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: the random number generator to draw from
+    ///
+    /// returns: a valid permutation, or `None` if there is none
+    pub fn sample<R>(&self, rng: &mut R) -> Option<Vec<T>>
+    where
+        R: Rng + ?Sized,
+    {
+        let n = self.values.len();
+        let indices = match &self.method {
+            Sampling::Exact(completions) => draw_exact(&self.allowed, n, completions, rng),
+            Sampling::Chain(start) => {
+                let steps = 50 * n * (usize::BITS - n.leading_zeros()) as usize;
+                random_matching_mcmc(&self.allowed, start.clone(), n, steps, rng)
+            }
+            Sampling::Unsatisfiable => return None,
+        };
+        Some(
+            indices
+                .into_iter()
+                .map(|j| self.values[j].clone())
+                .collect(),
+        )
+    }
+}
+
+/// completions[mask]: number of ways to fill the remaining positions if the values in `mask` have
+/// been used for the first popcount(mask) positions. With at most 20 values this is at most 20!,
+/// which fits in a u64.
+fn count_completions(allowed: &[Vec<bool>], n: usize) -> Vec<u64> {
+    let k = allowed.len();
+    let mut completions = vec![0u64; 1 << n];
+    for mask in (0..1usize << n).rev() {
+        let position = mask.count_ones() as usize;
+        completions[mask] = if position == k {
+            1
+        } else if position > k {
+            0
+        } else {
+            (0..n)
+                .filter(|&j| mask & (1 << j) == 0 && allowed[position][j])
+                .map(|j| completions[mask | (1 << j)])
+                .sum()
+        };
+    }
+    completions
+}
+
+/// Draw each next value proportionally to its number of completions
+fn draw_exact<R>(allowed: &[Vec<bool>], n: usize, completions: &[u64], rng: &mut R) -> Vec<usize>
+where
+    R: Rng + ?Sized,
+{
+    let mut mask = 0;
+    let mut result = Vec::with_capacity(allowed.len());
+    for row in allowed {
+        let mut draw = rng.random_range(0..completions[mask]);
+        let j = (0..n)
+            .filter(|&j| mask & (1 << j) == 0 && row[j])
+            .find(|&j| {
+                let weight = completions[mask | (1 << j)];
+                if draw < weight {
+                    true
+                } else {
+                    draw -= weight;
+                    false
+                }
+            })
+            .unwrap();
+        mask |= 1 << j;
+        result.push(j);
+    }
+    result
+}
+
+/// Run the Markov chain from a valid assignment of distinct value indices to the k positions of
+/// `allowed`
+fn random_matching_mcmc<R>(
+    allowed: &[Vec<bool>],
+    mut state: Vec<usize>,
+    n: usize,
+    steps: usize,
+    rng: &mut R,
+) -> Vec<usize>
+where
+    R: Rng + ?Sized,
+{
+    let k = allowed.len();
+    if n < 2 {
+        return state;
+    }
+
+    // The unused values take up positions k..n, where anything is allowed
+    let mut used = vec![false; n];
+    for &j in &state {
+        used[j] = true;
+    }
+    state.extend((0..n).filter(|&j| !used[j]));
+    let is_allowed = |position: usize, j: usize| position >= k || allowed[position][j];
+
+    for _ in 0..steps {
+        let a = rng.random_range(0..n);
+        let b = rng.random_range(0..n - 1);
+        let b = if b >= a { b + 1 } else { b };
+        if is_allowed(a, state[b]) && is_allowed(b, state[a]) {
+            state.swap(a, b);
+        }
+    }
+    state.truncate(k);
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cyclic_permutations_by_range, derangements_by_range, involutive_derangements_by_range,
+        restricted_permutations_by_map_index, restricted_permutations_by_map_value,
+    };
+    use itertools::Itertools;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_random_derangement_uniform() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for n in 4..=6 {
+            let population = derangements_by_range(n).collect_vec();
+            let draws = 100 * population.len();
+            assert_uniform(population, draws, || {
+                random_derangement(n, &mut rng).unwrap()
+            });
+        }
+    }
+
+    #[test]
+    fn test_random_derangement_edge_cases() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(random_derangement(0, &mut rng), Some(vec![]));
+        assert_eq!(random_derangement(1, &mut rng), None);
+        assert_eq!(random_derangement(2, &mut rng), Some(vec![1, 0]));
+        for _ in 0..100 {
+            let x = random_derangement(50, &mut rng).unwrap();
+            assert!(x.iter().enumerate().all(|(i, &v)| i != v));
+            assert_eq!(x.into_iter().sorted().collect_vec(), (0..50).collect_vec());
+        }
+    }
+
+    #[test]
+    fn test_random_involutive_derangement_uniform() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for n in [4, 6, 8] {
+            let population = involutive_derangements_by_range(n).collect_vec();
+            let draws = 100 * population.len();
+            assert_uniform(population, draws, || {
+                random_involutive_derangement(n, &mut rng).unwrap()
+            });
+        }
+        assert_eq!(random_involutive_derangement(0, &mut rng), Some(vec![]));
+        assert_eq!(random_involutive_derangement(3, &mut rng), None);
+    }
+
+    #[test]
+    fn test_random_cyclic_permutation_uniform() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for n in 3..=6 {
+            let population = cyclic_permutations_by_range(n).collect_vec();
+            let draws = 100 * population.len();
+            assert_uniform(population, draws, || {
+                random_cyclic_permutation(n, &mut rng).unwrap()
+            });
+        }
+        assert_eq!(random_cyclic_permutation(0, &mut rng), None);
+        assert_eq!(random_cyclic_permutation(2, &mut rng), Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn test_derange_in_place() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut single = ["a"];
+        assert!(!derange_in_place(&mut single, &mut rng));
+        for _ in 0..100 {
+            let mut words = ["a", "b", "c", "d", "e", "f"];
+            assert!(derange_in_place(&mut words, &mut rng));
+            assert!(words
+                .iter()
+                .zip(["a", "b", "c", "d", "e", "f"])
+                .all(|(x, y)| *x != y));
+        }
+    }
+
+    /// Check uniformity of draws over `population` (which may contain repetitions) with a
+    /// chi-squared test at p = 0.999, using the Wilson-Hilferty approximation of the critical value
+    fn assert_uniform<F>(population: Vec<Vec<usize>>, draws: usize, mut draw: F)
+    where
+        F: FnMut() -> Vec<usize>,
+    {
+        let expected = population.into_iter().counts();
+        let total: usize = expected.values().sum();
+        let mut observed: HashMap<Vec<usize>, usize> = HashMap::new();
+        for _ in 0..draws {
+            *observed.entry(draw()).or_default() += 1;
+        }
+        assert!(observed.keys().all(|x| expected.contains_key(x)));
+        let chi_squared: f64 = expected
+            .iter()
+            .map(|(x, &count)| {
+                let expected = (draws * count) as f64 / total as f64;
+                let observed = *observed.get(x).unwrap_or(&0) as f64;
+                (observed - expected).powi(2) / expected
+            })
+            .sum();
+        let df = (expected.len() - 1) as f64;
+        let critical = df * (1.0 - 2.0 / (9.0 * df) + 3.09 * (2.0 / (9.0 * df)).sqrt()).powi(3);
+        assert!(chi_squared < critical, "{chi_squared} >= {critical}");
+    }
+
+    #[test]
+    fn test_random_restricted_uniform() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let values = vec![0usize, 1, 1, 2, 3];
+        let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2]), (3, vec![0, 3])]);
+        let population =
+            restricted_permutations_by_map_index(values.clone().into_iter(), 4, restrict.clone())
+                .collect_vec();
+        assert_uniform(population, 10000, || {
+            random_restricted_permutation_by_map_index(
+                values.clone().into_iter(),
+                4,
+                &restrict,
+                &mut rng,
+            )
+            .unwrap()
+        });
+
+        let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2]), (3, vec![0, 3])]);
+        let population =
+            restricted_permutations_by_map_value(0..5, 5, restrict.clone()).collect_vec();
+        assert_uniform(population, 10000, || {
+            random_restricted_permutation_by_map_value(0..5, 5, &restrict, &mut rng).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_random_restricted_mcmc_uniform() {
+        // The Markov chain on its own, on a case where every valid permutation can be reached
+        let mut rng = StdRng::seed_from_u64(2);
+        let restriction = Restriction::from_fn(&(0..5).collect_vec(), 4, |i, &j| i != j);
+        let allowed = restriction.rows();
+        let population = Itertools::permutations(0..5, 4)
+            .filter(|x| x.iter().enumerate().all(|(i, &j)| i != j))
+            .collect_vec();
+        let start = restriction.matching().unwrap();
+        assert_uniform(population, 10000, || {
+            random_matching_mcmc(&allowed, start.clone(), 5, 100, &mut rng)
+        });
+    }
+
+    #[test]
+    fn test_random_restricted_large() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let restrict = HashMap::from_iter((0..40).map(|i| (i, vec![i, (i + 1) % 40])));
+        for k in [30, 40] {
+            let x =
+                random_restricted_permutation_by_map_index(0..40, k, &restrict, &mut rng).unwrap();
+            assert_eq!(x.len(), k);
+            assert_eq!(x.iter().unique().count(), k);
+            assert!(x
+                .iter()
+                .enumerate()
+                .all(|(i, &v)| !restrict[&i].contains(&v)));
+        }
+
+        // Index 0 and 1 can only hold value 0, so there is no valid permutation
+        let restrict = HashMap::from_iter((0..2).map(|i| (i, (1..40).collect_vec())));
+        assert_eq!(
+            random_restricted_permutation_by_map_index(0..40, 40, &restrict, &mut rng),
+            None
+        );
+        assert_eq!(
+            random_restricted_permutation_by_map_index(0..10, 10, &restrict, &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn test_random_restricted_seeded() {
+        let restrict = HashMap::from([(0, vec![0]), (5, vec![5, 6])]);
+        for n in [10, 30] {
+            let draw = |seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                random_restricted_permutation_by_map_index(0..n, n, &restrict, &mut rng)
+            };
+            assert_eq!(draw(7), draw(7));
+        }
+    }
+
+    #[test]
+    fn test_sampler_reuse() {
+        // A sampler that is built once draws the same as the single draws, for both methods
+        let restrict = HashMap::from([(0, vec![0]), (5, vec![5, 6])]);
+        for n in [10, 30] {
+            let sampler = RestrictedPermutationSampler::from_map_index(0..n, n, &restrict);
+            let (mut rng, mut other) = (StdRng::seed_from_u64(8), StdRng::seed_from_u64(8));
+            for _ in 0..20 {
+                assert_eq!(
+                    sampler.sample(&mut rng),
+                    random_restricted_permutation_by_map_index(0..n, n, &restrict, &mut other)
+                );
+            }
+        }
+        let restrict = HashMap::from([(0, vec![0, 1])]);
+        let sampler = RestrictedPermutationSampler::from_map_value(0..1, 1, &restrict);
+        assert_eq!(sampler.sample(&mut StdRng::seed_from_u64(0)), None);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::counting;
+
+/// The largest n for which all derangements of 0..n can be ranked in a `u128`
+const MAX_RANK_N: usize = 34;
+
+/// Table of E(m, r): the number of permutations of m elements where r given elements are not
+/// fixed points, for all r <= m <= n.
+fn completions_table(n: usize) -> Vec<Vec<u128>> {
+    assert!(
+        n <= MAX_RANK_N,
+        "can't rank derangements of more than 34 elements"
+    );
+    let mut table: Vec<Vec<u128>> = Vec::with_capacity(n + 1);
+    let mut factorial = 1;
+    for m in 0..=n {
+        if m > 0 {
+            factorial *= m as u128;
+        }
+        let mut row = vec![factorial];
+        for r in 1..=m {
+            row.push(row[r - 1] - table[m - 1][r - 1]);
+        }
+        table.push(row);
+    }
+    table
+}
+
+fn assert_derangement(derangement: &[usize]) {
+    let mut seen = vec![false; derangement.len()];
+    for (i, &x) in derangement.iter().enumerate() {
+        assert!(
+            x < derangement.len() && !seen[x] && x != i,
+            "input is not a derangement of 0..{}",
+            derangement.len()
+        );
+        seen[x] = true;
+    }
+}
+
+/// Rank a derangement of 0..n in the lexicographic order of all derangements of 0..n, which is the
+/// order of `derangements(0..n, n)`.
+///
+/// # Arguments
+///
+/// * `derangement`: the derangement to rank
+///
+/// returns: the number of derangements of the same length that precede it
+///
+/// # Panics
+///
+/// If the input is not a derangement of 0..n, or if n is more than 34.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::derangement_rank;
+/// assert_eq!(derangement_rank(&[1, 0, 3, 2]), 0);
+/// assert_eq!(derangement_rank(&[3, 2, 1, 0]), 8);
+/// ```
+pub fn derangement_rank(derangement: &[usize]) -> u128 {
+    assert_derangement(derangement);
+    let n = derangement.len();
+    let table = completions_table(n);
+    let mut used = vec![false; n];
+    let mut rank = 0;
+    for (i, &x) in derangement.iter().enumerate() {
+        // Later positions whose own value is still available, and so have to avoid it
+        let restricted = (i + 1..n).filter(|&j| !used[j]).count();
+        let remaining = n - i - 1;
+        for c in (0..x).filter(|&c| !used[c] && c != i) {
+            rank += table[remaining][restricted - usize::from(c > i)];
+        }
+        used[x] = true;
+    }
+    rank
+}
+
+/// Get the derangement of 0..n with a given rank in the lexicographic order of all derangements of
+/// 0..n, which is the order of `derangements(0..n, n)`.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `rank`: the number of derangements that precede the result
+///
+/// returns: the derangement with that rank
+///
+/// # Panics
+///
+/// If the rank is not smaller than the number of derangements of 0..n, or if n is more than 34.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{derangement_rank, derangement_unrank};
+/// assert_eq!(derangement_unrank(4, 8), [3, 2, 1, 0]);
+/// assert_eq!(derangement_rank(&derangement_unrank(20, 1234567890)), 1234567890);
+/// ```
+pub fn derangement_unrank(n: usize, mut rank: u128) -> Vec<usize> {
+    let table = completions_table(n);
+    assert!(rank < table[n][n], "rank out of range");
+    let mut used = vec![false; n];
+    let mut derangement = Vec::with_capacity(n);
+    for i in 0..n {
+        let restricted = (i + 1..n).filter(|&j| !used[j]).count();
+        let remaining = n - i - 1;
+        for c in (0..n).filter(|&c| !used[c] && c != i) {
+            let count = table[remaining][restricted - usize::from(c > i)];
+            if rank < count {
+                derangement.push(c);
+                used[c] = true;
+                break;
+            }
+            rank -= count;
+        }
+    }
+    derangement
+}
+
+/// Rank a derangement of 0..n in the order in which `derangements_by_range(n)` generates it.
+///
+/// # Arguments
+///
+/// * `derangement`: the derangement to rank
+///
+/// returns: the number of derangements that `derangements_by_range` generates before it
+///
+/// # Panics
+///
+/// If the input is not a derangement of 0..n, or if n is more than 34.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{derangement_rank_by_range, derangements_by_range};
+/// assert_eq!(derangement_rank_by_range(&[2, 0, 1]), 0);
+/// assert_eq!(derangement_rank_by_range(&[1, 2, 0]), 1);
+/// for (i, x) in derangements_by_range(5).enumerate() {
+///     assert_eq!(derangement_rank_by_range(&x), i as u128);
+/// }
+/// ```
+pub fn derangement_rank_by_range(derangement: &[usize]) -> u128 {
+    assert_derangement(derangement);
+    assert!(
+        derangement.len() <= MAX_RANK_N,
+        "can't rank derangements of more than 34 elements"
+    );
+    rank_by_range(derangement.to_vec())
+}
+
+fn rank_by_range(mut derangement: Vec<usize>) -> u128 {
+    let n = derangement.len();
+    if n == 0 {
+        return 0;
+    }
+    let last = n - 1;
+    let c = derangement.iter().position(|&x| x == last).unwrap();
+    if derangement[last] != c {
+        // Part 1: the last element was swapped with the one at index c of a derangement of n - 1
+        derangement[c] = derangement[last];
+        derangement.pop();
+        last as u128 * rank_by_range(derangement) + c as u128
+    } else {
+        // Part 2: c and the last element form a 2-cycle around a derangement of n - 2
+        derangement.pop();
+        derangement.remove(c);
+        for x in derangement.iter_mut().filter(|x| **x > c) {
+            *x -= 1;
+        }
+        let part_1 = last as u128 * counting::subfactorial::<u128>(last).unwrap();
+        part_1 + last as u128 * rank_by_range(derangement) + (last - 1 - c) as u128
+    }
+}
+
+/// Get the derangement of 0..n that `derangements_by_range(n)` generates at a given position.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to derange
+/// * `rank`: the number of derangements that `derangements_by_range` generates before the result
+///
+/// returns: the derangement with that rank
+///
+/// # Panics
+///
+/// If the rank is not smaller than the number of derangements of 0..n, or if n is more than 34.
+///
+/// # Examples
+///
+/// ```
+/// use derangements::{derangement_unrank_by_range, derangements_by_range};
+/// assert_eq!(derangement_unrank_by_range(3, 1), [1, 2, 0]);
+/// assert_eq!(derangement_unrank_by_range(6, 200), derangements_by_range(6).nth(200).unwrap());
+/// ```
+pub fn derangement_unrank_by_range(n: usize, rank: u128) -> Vec<usize> {
+    assert!(
+        n <= MAX_RANK_N,
+        "can't rank derangements of more than 34 elements"
+    );
+    assert!(
+        rank < counting::subfactorial(n).unwrap(),
+        "rank out of range"
+    );
+    unrank_by_range(n, rank)
+}
+
+fn unrank_by_range(n: usize, rank: u128) -> Vec<usize> {
+    if n == 0 {
+        return vec![];
+    }
+    let last = n - 1;
+    let part_1 = last as u128 * counting::subfactorial::<u128>(last).unwrap();
+    if rank < part_1 {
+        let c = (rank % last as u128) as usize;
+        let mut derangement = unrank_by_range(last, rank / last as u128);
+        derangement.push(last);
+        derangement.swap(c, last);
+        derangement
+    } else {
+        let rank = rank - part_1;
+        let c = last - 1 - (rank % last as u128) as usize;
+        let mut derangement = unrank_by_range(last - 1, rank / last as u128);
+        for x in derangement.iter_mut().filter(|x| **x >= c) {
+            *x += 1;
+        }
+        derangement.insert(c, last);
+        derangement.push(c);
+        derangement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{derangements, derangements_by_range};
+
+    #[test]
+    fn test_rank_lexicographic() {
+        for n in 0..=9 {
+            let mut count = 0;
+            for (i, x) in derangements(0..n, n).enumerate() {
+                assert_eq!(derangement_rank(&x), i as u128);
+                assert_eq!(derangement_unrank(n, i as u128), x);
+                count += 1;
+            }
+            assert_eq!(count, counting::subfactorial::<u128>(n).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_rank_by_range() {
+        for n in 0..=9 {
+            for (i, x) in derangements_by_range(n).enumerate() {
+                assert_eq!(derangement_rank_by_range(&x), i as u128);
+                assert_eq!(derangement_unrank_by_range(n, i as u128), x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rank_large() {
+        let last = counting::subfactorial::<u128>(34).unwrap() - 1;
+        assert_eq!(
+            derangement_unrank(34, last),
+            (0..34).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            derangement_rank(&derangement_unrank(34, last / 3)),
+            last / 3
+        );
+        let x = derangement_unrank_by_range(34, last / 3);
+        assert_eq!(derangement_rank_by_range(&x), last / 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rank_not_derangement() {
+        derangement_rank(&[1, 0, 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unrank_out_of_range() {
+        derangement_unrank(4, 9);
+    }
+}
@@ -1,37 +1,62 @@
-use crate::fast_permutations;
-use crate::fast_permutations::FastPermutations;
+use crate::pairs::PairConstraints;
+use crate::restriction::{perfect_matchings, PerfectMatchings, Restriction};
 use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::iter::FusedIterator;
 
-macro_rules! clone_fields {  // Note: copied from Itertools - need to attribute before release - or just directly write the clone func
-    ($($field:ident),*) => {
-        #[inline]
-        fn clone(&self) -> Self {
-            Self {
-                $($field: self.$field.clone(),)*
-            }
-        }
-    }
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "I::Item: Serialize",
+            deserialize = "I::Item: Deserialize<'de>"
+        ),
+        try_from = "RestrictedPermutationsState<I>"
+    )
+)]
+pub struct RestrictedPermutations<I: Iterator> {
+    values: Vec<I::Item>,
+    matchings: PerfectMatchings,
+}
+
+/// The fields of a deserialized `RestrictedPermutations`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I::Item: Deserialize<'de>"))]
+struct RestrictedPermutationsState<I: Iterator> {
+    values: Vec<I::Item>,
+    matchings: PerfectMatchings,
 }
-macro_rules! debug_fmt_fields {  // Note: copied from Itertools - need to attribute before release - or just directly write the fmt func
-    ($tyname:ident, $($($field:tt).+),*) => {
-        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-            f.debug_struct(stringify!($tyname))
-                $(
-              .field(stringify!($($field).+), &self.$($field).+)
-              )*
-              .finish()
+
+#[cfg(feature = "serde")]
+impl<I: Iterator> TryFrom<RestrictedPermutationsState<I>> for RestrictedPermutations<I> {
+    type Error = &'static str;
+
+    fn try_from(state: RestrictedPermutationsState<I>) -> Result<Self, Self::Error> {
+        if state.values.len() != state.matchings.restriction().values() {
+            return Err("values of a different length than the restriction");
         }
+        Ok(RestrictedPermutations {
+            values: state.values,
+            matchings: state.matchings,
+        })
     }
 }
 
-#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
-pub struct RestrictedPermutations<I: Iterator> {
-    permutations: FastPermutations<I>,
-    restrict: Vec<I::Item>,
+impl<I: Iterator> RestrictedPermutations<I> {
+    /// The permutations of `values` that satisfy the restriction.
+    pub(crate) fn new(values: Vec<I::Item>, restriction: Restriction) -> Self {
+        RestrictedPermutations {
+            values,
+            matchings: perfect_matchings(restriction),
+        }
+    }
 }
 
 impl<I> Clone for RestrictedPermutations<I>
@@ -39,7 +64,7 @@ where
     I: Clone + Iterator,
     I::Item: Clone,
 {
-    clone_fields!(permutations, restrict);
+    clone_fields!(values, matchings);
 }
 
 impl<I> Debug for RestrictedPermutations<I>
@@ -47,7 +72,52 @@ where
     I: Iterator + Debug,
     I::Item: Debug,
 {
-    debug_fmt_fields!(RestrictedPermutations, permutations, restrict);
+    debug_fmt_fields!(RestrictedPermutations, values, matchings);
+}
+
+/// Permute k or all elements of an iterable while only placing values where a `Restriction`
+/// allows them.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `restriction`: the allowed pairs of (index in the iterable, index in the permutation), for
+///   permutations of as many elements as the restriction has positions
+///
+/// returns: iterable with the permutations
+///
+/// # Panics
+///
+/// If the number of items differs from the number of values of the restriction.
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{restricted_permutations_by_restriction, Restriction};
+///
+/// // The first item can only be at the end, and the last one not at the start
+/// let mut restriction = Restriction::new(3, 3);
+/// restriction.forbid(0, 0);
+/// restriction.forbid(0, 1);
+/// restriction.forbid(2, 0);
+/// assert_equal(restricted_permutations_by_restriction(vec!["x", "y", "z"].into_iter(), restriction),
+///     [["y", "z", "x"]]);
+/// ```
+pub fn restricted_permutations_by_restriction<I>(
+    iter: I,
+    restriction: Restriction,
+) -> RestrictedPermutations<I>
+where
+    I: Iterator,
+{
+    let values = Vec::from_iter(iter);
+    assert_eq!(
+        values.len(),
+        restriction.values(),
+        "the restriction should have a value for each item"
+    );
+    RestrictedPermutations::new(values, restriction)
 }
 
 /// Permute k or all elements of an iterable while excluding based on an input restriction
@@ -72,7 +142,7 @@ where
 ///
 /// // Otherwise, the result will be different depending on the restrict input
 /// assert_equal(restricted_permutations(vec![1, 0, 2, 2].into_iter(), 3, vec![1, 0, 2].into_iter()),
-///     [[0, 2, 1], [2, 1, 0], [0, 2, 1], [2, 2, 0], [2, 2, 0], [2, 1, 0], [2, 2, 1], [2, 2, 1]]);
+///     [[0, 2, 1], [0, 2, 1], [2, 1, 0], [2, 2, 1], [2, 2, 0], [2, 1, 0], [2, 2, 1], [2, 2, 0]]);
 ///
 /// // This can also be applied to a non-integer type in the vector
 /// assert_equal(restricted_permutations(vec!["I", "like", "permutations"].into_iter(), 2, vec!["like", "I"].into_iter()),
@@ -83,10 +153,9 @@ where
     I: Iterator,
     I::Item: Clone + Ord,
 {
-    RestrictedPermutations {
-        permutations: fast_permutations(iter, k),
-        restrict: restrict.collect_vec(),
-    }
+    let values = iter.collect_vec();
+    let restriction = Restriction::from_sequence(&values, k, &restrict.collect_vec());
+    RestrictedPermutations::new(values, restriction)
 }
 
 /// Permute k or all elements of an iterable while excluding any results where one of the
@@ -122,61 +191,9 @@ where
     I: Iterator + Clone,
     I::Item: Clone + Ord,
 {
-    let permutations = fast_permutations(iter.clone(), k);
-    let restrict = iter.collect_vec();
-    RestrictedPermutations {
-        permutations,
-        restrict,
-    }
-}
-
-impl<I> Iterator for RestrictedPermutations<I>
-where
-    I: Iterator,
-    I::Item: Clone + Ord + Copy,
-{
-    type Item = Vec<I::Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.permutations.next() {
-            None => return None,
-            Some(x) => {
-                if !x.iter().enumerate().any(|x| self.restrict[x.0] == *x.1) {
-                    return Some(x);
-                }
-            }
-        };
-        self.next()
-    }
-}
-
-impl<I> FusedIterator for RestrictedPermutations<I>
-where
-    I: Iterator,
-    I::Item: Clone + Ord + Copy + Debug,
-{
-}
-
-#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
-pub struct RestrictedPermutationsByMapIndex<I: Iterator> {
-    permutations: FastPermutations<I>,
-    restrict: HashMap<usize, Vec<I::Item>>,
-}
-
-impl<I> Clone for RestrictedPermutationsByMapIndex<I>
-where
-    I: Clone + Iterator,
-    I::Item: Clone,
-{
-    clone_fields!(permutations, restrict);
-}
-
-impl<I> Debug for RestrictedPermutationsByMapIndex<I>
-where
-    I: Iterator + Debug,
-    I::Item: Debug,
-{
-    debug_fmt_fields!(RestrictedPermutationsByMapIndex, permutations, restrict);
+    let values = iter.collect_vec();
+    let restriction = Restriction::from_sequence(&values, k, &values);
+    RestrictedPermutations::new(values, restriction)
 }
 
 /// Permute k or all elements of an iterable while excluding based on an input restriction
@@ -208,70 +225,14 @@ pub fn restricted_permutations_by_map_index<I>(
     iter: I,
     k: usize,
     restrict: HashMap<usize, Vec<I::Item>>,
-) -> RestrictedPermutationsByMapIndex<I>
+) -> RestrictedPermutations<I>
 where
     I: Iterator,
     I::Item: Clone + Ord,
 {
-    RestrictedPermutationsByMapIndex {
-        permutations: fast_permutations(iter, k),
-        restrict,
-    }
-}
-
-impl<I> Iterator for RestrictedPermutationsByMapIndex<I>
-where
-    I: Iterator,
-    I::Item: Clone + Ord + Copy,
-{
-    type Item = Vec<I::Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.permutations.next() {
-            None => return None,
-            Some(x) => {
-                if !x.iter().enumerate().any(|x| {
-                    if self.restrict.contains_key(&x.0) {
-                        self.restrict[&x.0].contains(x.1)
-                    } else {
-                        false
-                    }
-                }) {
-                    return Some(x);
-                }
-            }
-        };
-        self.next()
-    }
-}
-
-impl<I> FusedIterator for RestrictedPermutationsByMapIndex<I>
-where
-    I: Iterator,
-    I::Item: Clone + Ord + Copy + Debug,
-{
-}
-
-#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
-pub struct RestrictedPermutationsByMapValue<I: Iterator> {
-    permutations: FastPermutations<I>,
-    restrict: HashMap<I::Item, Vec<usize>>,
-}
-
-impl<I> Clone for RestrictedPermutationsByMapValue<I>
-where
-    I: Clone + Iterator,
-    I::Item: Clone,
-{
-    clone_fields!(permutations, restrict);
-}
-
-impl<I> Debug for RestrictedPermutationsByMapValue<I>
-where
-    I: Iterator + Debug,
-    I::Item: Debug,
-{
-    debug_fmt_fields!(RestrictedPermutationsByMapValue, permutations, restrict);
+    let values = iter.collect_vec();
+    let restriction = Restriction::from_map_index(&values, k, &restrict);
+    RestrictedPermutations::new(values, restriction)
 }
 
 /// Permute k or all elements of an iterable while excluding based on an input restriction
@@ -303,47 +264,134 @@ pub fn restricted_permutations_by_map_value<I>(
     iter: I,
     k: usize,
     restrict: HashMap<I::Item, Vec<usize>>,
-) -> RestrictedPermutationsByMapValue<I>
+) -> RestrictedPermutations<I>
 where
     I: Iterator,
-    I::Item: Clone + Ord,
+    I::Item: Clone + Ord + Hash,
 {
-    RestrictedPermutationsByMapValue {
-        permutations: fast_permutations(iter, k),
-        restrict,
+    let values = iter.collect_vec();
+    let restriction = Restriction::from_map_value(&values, k, &restrict);
+    RestrictedPermutations::new(values, restriction)
+}
+
+/// Permute k or all elements of an iterable while only placing an element at an index if a
+/// predicate allows it.
+///
+/// The predicate is called exactly once for every pair of an index below k and an element, all
+/// before the first permutation is produced, so each placement that it rejects is cut from the
+/// search right away instead of filtering complete permutations. It therefore can't depend on the
+/// elements placed at the other indices. The permutations are in the same order as those of the
+/// other restricted permutations.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `allowed`: predicate on the index in the permutation and the element placed there
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::restricted_permutations_by_fn;
+///
+/// // No element may move more than one position away
+/// assert_equal(restricted_permutations_by_fn(0..4usize, 4, |index, &x| index.abs_diff(x) <= 1),
+///     [[0, 1, 2, 3], [0, 1, 3, 2], [0, 2, 1, 3], [1, 0, 2, 3], [1, 0, 3, 2]]);
+///
+/// // Alternate between vowels and consonants, starting with a consonant
+/// let vowel = |x: &char| "aeiou".contains(*x);
+/// assert_equal(restricted_permutations_by_fn("abde".chars(), 4, |index, x| vowel(x) == (index % 2 == 1)),
+///     [['b', 'a', 'd', 'e'], ['b', 'e', 'd', 'a'], ['d', 'a', 'b', 'e'], ['d', 'e', 'b', 'a']]);
+///
+/// // The predicate is called for the 2 indices and 3 elements when the iterator is created
+/// let mut calls = 0;
+/// let iter = restricted_permutations_by_fn(0..3usize, 2, |_, _| { calls += 1; true });
+/// assert_eq!(calls, 6);
+/// assert_eq!(iter.count(), 6);
+/// ```
+pub fn restricted_permutations_by_fn<I, F>(
+    iter: I,
+    k: usize,
+    allowed: F,
+) -> RestrictedPermutations<I>
+where
+    I: Iterator,
+    F: FnMut(usize, &I::Item) -> bool,
+{
+    let values = iter.collect_vec();
+    let restriction = Restriction::from_fn(&values, k, allowed);
+    RestrictedPermutations::new(values, restriction)
+}
+
+/// Permute k or all elements of an iterable while keeping to constraints between pairs of
+/// elements: elements that may not be next to each other, and elements that have to come before
+/// others.
+///
+/// Partial permutations are cut as soon as a placement breaks a constraint. In a circular
+/// arrangement, the first and last element are also checked as neighbours.
+///
+/// # Arguments
+///
+/// * `iterable`: the iterable of items to permute
+/// * `k`: `usize` integer that determines how many elements each permutation should have
+/// * `pairs`: the constraints, referring to the items by their index in the iterable
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{restricted_permutations_by_pairs, PairConstraints};
+///
+/// // Run test 2 before test 0, and never test 0 right after test 1 or the other way around
+/// let mut pairs = PairConstraints::new();
+/// pairs.require_before(2, 0);
+/// pairs.forbid_adjacent(0, 1);
+/// assert_equal(restricted_permutations_by_pairs(vec!["t0", "t1", "t2", "t3"].into_iter(), 4, pairs),
+///     [["t1", "t2", "t0", "t3"], ["t1", "t2", "t3", "t0"], ["t1", "t3", "t2", "t0"],
+///      ["t2", "t0", "t3", "t1"], ["t2", "t1", "t3", "t0"], ["t3", "t1", "t2", "t0"]]);
+/// ```
+pub fn restricted_permutations_by_pairs<I>(
+    iter: I,
+    k: usize,
+    pairs: PairConstraints,
+) -> RestrictedPermutations<I>
+where
+    I: Iterator,
+{
+    let values = iter.collect_vec();
+    let restriction = Restriction::new(values.len(), k);
+    RestrictedPermutations {
+        values,
+        matchings: perfect_matchings(restriction).with_pairs(pairs),
     }
 }
 
-impl<I> Iterator for RestrictedPermutationsByMapValue<I>
+impl<I> Iterator for RestrictedPermutations<I>
 where
     I: Iterator,
-    I::Item: Clone + Ord + Copy + Hash,
+    I::Item: Clone,
 {
     type Item = Vec<I::Item>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.permutations.next() {
-            None => return None,
-            Some(x) => {
-                if !x.iter().enumerate().any(|x| {
-                    if self.restrict.contains_key(x.1) {
-                        self.restrict[x.1].contains(&x.0)
-                    } else {
-                        false
-                    }
-                }) {
-                    return Some(x);
-                }
-            }
-        };
-        self.next()
+        let indices = self.matchings.advance()?;
+        Some(indices.iter().map(|&i| self.values[i].clone()).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.matchings.size_hint()
     }
 }
 
-impl<I> FusedIterator for RestrictedPermutationsByMapValue<I>
+impl<I> FusedIterator for RestrictedPermutations<I>
 where
     I: Iterator,
-    I::Item: Clone + Ord + Copy + Debug + Hash,
+    I::Item: Clone,
 {
 }
 
@@ -383,4 +431,139 @@ mod tests {
             [[0, 1], [0, 2], [2, 1]],
         );
     }
+
+    #[test]
+    fn test_k_restricted_no_duplicates() {
+        // With k < n, each ordered selection should appear once, not once per ordering of the rest
+        assert_eq!(
+            restricted_permutations_by_map_index(0..4, 2, HashMap::new()).count(),
+            12
+        );
+        assert_equal(
+            restricted_permutations_by_self(0..4, 2).sorted(),
+            Itertools::permutations(0..4, 2)
+                .filter(|x| x[0] != 0 && x[1] != 1)
+                .sorted(),
+        );
+    }
+
+    #[test]
+    fn test_size_hint() {
+        let restrict = HashMap::from([(0, vec![0, 1]), (1, vec![1, 2])]);
+        let iter = restricted_permutations_by_map_index(0..5, 4, restrict);
+        assert_eq!(iter.size_hint(), (0, Some(120)));
+        let count = iter.count();
+        assert!(count > 0 && count < 120);
+    }
+
+    #[test]
+    fn test_long_rejection_streak() {
+        // The last two positions only allow 0 and 1, which is only found out once the positions
+        // before them are filled, so the first 2 * 8! partial permutations are all rejected
+        let restrict = HashMap::from([(8, (2..10).collect_vec()), (9, (2..10).collect_vec())]);
+        let mut iter = restricted_permutations_by_map_index(0..10, 10, restrict);
+        assert_eq!(iter.next(), Some(vec![2, 3, 4, 5, 6, 7, 8, 9, 0, 1]));
+        assert_eq!(iter.count(), 2 * 40320 - 1);
+        let restrict = HashMap::from_iter((2..10).map(|x| (x, vec![8, 9])));
+        let mut iter = restricted_permutations_by_map_value(0..10, 10, restrict);
+        assert_eq!(iter.next(), Some(vec![2, 3, 4, 5, 6, 7, 8, 9, 0, 1]));
+        assert_eq!(iter.count(), 2 * 40320 - 1);
+    }
+
+    #[test]
+    fn test_by_fn() {
+        // The same permutations as filtering, and the same order as an equivalent restriction
+        for n in 0..=7 {
+            for k in 0..=n {
+                let allowed = |index: usize, x: &usize| index.abs_diff(*x) <= 2 && *x != index;
+                assert_equal(
+                    restricted_permutations_by_fn(0..n, k, allowed).sorted(),
+                    Itertools::permutations(0..n, k)
+                        .filter(|x| x.iter().enumerate().all(|(i, x)| allowed(i, x)))
+                        .sorted(),
+                );
+                let restrict = HashMap::from_iter(
+                    (0..k).map(|i| (i, (0..n).filter(|x| !allowed(i, x)).collect_vec())),
+                );
+                assert_equal(
+                    restricted_permutations_by_fn(0..n, k, allowed),
+                    restricted_permutations_by_map_index(0..n, k, restrict),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_by_pairs() {
+        // Compare with filtering, for linear and circular arrangements of k out of n values
+        let adjacent = |x: &[usize], circular: bool| {
+            let mut pairs = x.iter().copied().tuple_windows().collect_vec();
+            if circular && x.len() > 2 {
+                pairs.push((x[x.len() - 1], x[0]));
+            }
+            pairs
+        };
+        for n in 0..=7 {
+            for circular in [false, true] {
+                let mut pairs = if circular {
+                    PairConstraints::circular()
+                } else {
+                    PairConstraints::new()
+                };
+                for i in 0..n / 2 {
+                    pairs.forbid_adjacent(i, n - 1 - i);
+                }
+                pairs.forbid_adjacent(0, 1);
+                pairs.require_before(n.saturating_sub(1), 0);
+                pairs.require_before(2, 1);
+                for k in 0..=n {
+                    let expected = Itertools::permutations(0..n, k).filter(|x| {
+                        let before = |a, b| {
+                            let position = |v| x.iter().position(|&y| y == v);
+                            match (position(a), position(b)) {
+                                (Some(i), Some(j)) => i < j || a == b,
+                                _ => true,
+                            }
+                        };
+                        adjacent(x, circular)
+                            .iter()
+                            .all(|&(a, b)| (a.min(b), a.max(b)) != (0, 1) && a + b + 1 != n)
+                            && before(n.saturating_sub(1), 0)
+                            && before(2, 1)
+                    });
+                    assert_equal(
+                        restricted_permutations_by_pairs(0..n, k, pairs.clone()).sorted(),
+                        expected.sorted(),
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = restricted_permutations(0..5, 4, 1..5);
+        iter.nth(10);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: RestrictedPermutations<std::ops::Range<i32>> =
+            serde_json::from_str(&json).unwrap();
+        assert_equal(resumed, iter);
+
+        let restrict = HashMap::from([(0, vec![0, 1]), (2, vec![1, 2])]);
+        let mut iter = restricted_permutations_by_map_index(0..5, 4, restrict);
+        iter.nth(10);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: RestrictedPermutations<std::ops::Range<i32>> =
+            serde_json::from_str(&json).unwrap();
+        assert_equal(resumed, iter);
+
+        let restrict = HashMap::from([(0, vec![0, 1]), (2, vec![1, 2])]);
+        let mut iter = restricted_permutations_by_map_value(0..5, 4, restrict);
+        iter.nth(10);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: RestrictedPermutations<std::ops::Range<i32>> =
+            serde_json::from_str(&json).unwrap();
+        assert_equal(resumed, iter);
+    }
 }
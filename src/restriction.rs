@@ -0,0 +1,634 @@
+use crate::backtracking::Backtracking;
+use crate::counting;
+use crate::pairs::PairConstraints;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::iter::FusedIterator;
+
+const WORD: usize = u64::BITS as usize;
+
+/// Which values may be placed at which positions of a k-permutation of n values.
+///
+/// Values are referred to by their index in the input, and positions by their index in the
+/// output. The matrix is stored as one bitset of allowed values per position. All restriction
+/// shapes of this crate can be converted into a `Restriction`, and `restricted_permutations_by_restriction`
+/// enumerates the permutations that satisfy it.
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{restricted_permutations_by_restriction, Restriction};
+///
+/// // Value 0 can't be at position 0, and value 2 can't be at position 1
+/// let mut restriction = Restriction::new(3, 3);
+/// restriction.forbid(0, 0);
+/// restriction.forbid(2, 1);
+/// assert!(restriction.is_satisfiable());
+/// assert_equal(restricted_permutations_by_restriction(vec!['a', 'b', 'c'].into_iter(), restriction),
+///     [['b', 'a', 'c'], ['c', 'a', 'b'], ['c', 'b', 'a']]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RestrictionState"))]
+pub struct Restriction {
+    values: usize,
+    positions: usize,
+    words: usize,
+    allowed: Vec<u64>,
+}
+
+/// The fields of a deserialized `Restriction`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RestrictionState {
+    values: usize,
+    positions: usize,
+    words: usize,
+    allowed: Vec<u64>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RestrictionState> for Restriction {
+    type Error = &'static str;
+
+    fn try_from(state: RestrictionState) -> Result<Self, Self::Error> {
+        if state.words != state.values.div_ceil(WORD)
+            || Some(state.allowed.len()) != state.words.checked_mul(state.positions)
+        {
+            return Err("allowed values of the wrong length");
+        }
+
+        // Only the bits of the values themselves may be set
+        let full = Restriction::new(state.values, state.positions);
+        let mut zipped = full.allowed.iter().zip(&state.allowed);
+        if zipped.any(|(full, allowed)| allowed & !full != 0) {
+            return Err("allowed values out of range");
+        }
+        Ok(Restriction {
+            allowed: state.allowed,
+            ..full
+        })
+    }
+}
+
+impl Restriction {
+    /// Create a restriction for k-permutations of n values where everything is allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the number of values n to permute
+    /// * `positions`: the number of positions k of each permutation
+    ///
+    /// returns: the restriction
+    pub fn new(values: usize, positions: usize) -> Self {
+        let words = values.div_ceil(WORD);
+        let mut allowed = vec![u64::MAX; words * positions];
+        if !values.is_multiple_of(WORD) {
+            for position in 0..positions {
+                allowed[(position + 1) * words - 1] = (1 << (values % WORD)) - 1;
+            }
+        }
+        Restriction {
+            values,
+            positions,
+            words,
+            allowed,
+        }
+    }
+
+    /// The restriction of `derangements`: a value can't be at the position that equals it.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the values to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    ///
+    /// returns: the restriction
+    pub fn from_indices<T>(values: &[T], k: usize) -> Self
+    where
+        T: Clone,
+        usize: From<T>,
+    {
+        let mut restriction = Restriction::new(values.len(), k);
+        for (value, x) in values.iter().enumerate() {
+            let position = usize::from(x.clone());
+            if position < k {
+                restriction.forbid(value, position);
+            }
+        }
+        restriction
+    }
+
+    /// The restriction of `restricted_permutations`: a value can't be at a position where it
+    /// equals the element of `restrict`.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the values to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `restrict`: the elements to use as restriction, one per position
+    ///
+    /// returns: the restriction
+    pub fn from_sequence<T: PartialEq>(values: &[T], k: usize, restrict: &[T]) -> Self {
+        let mut restriction = Restriction::new(values.len(), k);
+        for (position, r) in restrict.iter().enumerate().take(k) {
+            for (value, x) in values.iter().enumerate() {
+                if x == r {
+                    restriction.forbid(value, position);
+                }
+            }
+        }
+        restriction
+    }
+
+    /// The restriction of `restricted_permutations_by_map_index`: the values that can't be at
+    /// each position.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the values to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `restrict`: `HashMap<usize, Vec<T>>`, indicating which elements can not be at an index
+    ///
+    /// returns: the restriction
+    pub fn from_map_index<T: PartialEq>(
+        values: &[T],
+        k: usize,
+        restrict: &HashMap<usize, Vec<T>>,
+    ) -> Self {
+        let mut restriction = Restriction::new(values.len(), k);
+        for (&position, r) in restrict.iter().filter(|(&position, _)| position < k) {
+            for (value, x) in values.iter().enumerate() {
+                if r.contains(x) {
+                    restriction.forbid(value, position);
+                }
+            }
+        }
+        restriction
+    }
+
+    /// The restriction of `restricted_permutations_by_map_value`: the positions at which each
+    /// value can't be.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the values to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `restrict`: `HashMap<T, Vec<usize>>`, indicating at which indices an element can't be
+    ///
+    /// returns: the restriction
+    pub fn from_map_value<T: Eq + Hash>(
+        values: &[T],
+        k: usize,
+        restrict: &HashMap<T, Vec<usize>>,
+    ) -> Self {
+        let mut restriction = Restriction::new(values.len(), k);
+        for (value, x) in values.iter().enumerate() {
+            for &position in restrict.get(x).into_iter().flatten() {
+                if position < k {
+                    restriction.forbid(value, position);
+                }
+            }
+        }
+        restriction
+    }
+
+    /// The restriction of `restricted_permutations_by_fn`: a value can only be at the positions
+    /// for which `allowed(position, &value)` holds.
+    ///
+    /// # Arguments
+    ///
+    /// * `values`: the values to permute
+    /// * `k`: `usize` integer that determines how many elements each permutation should have
+    /// * `allowed`: predicate on the index in the permutation and the value placed there
+    ///
+    /// returns: the restriction
+    pub fn from_fn<T, F>(values: &[T], k: usize, mut allowed: F) -> Self
+    where
+        F: FnMut(usize, &T) -> bool,
+    {
+        let mut restriction = Restriction::new(values.len(), k);
+        for position in 0..k {
+            for (value, x) in values.iter().enumerate() {
+                if !allowed(position, x) {
+                    restriction.forbid(value, position);
+                }
+            }
+        }
+        restriction
+    }
+
+    /// The number of values n to permute.
+    pub fn values(&self) -> usize {
+        self.values
+    }
+
+    /// The number of positions k of each permutation.
+    pub fn positions(&self) -> usize {
+        self.positions
+    }
+
+    /// Whether the value with the given index may be placed at a position.
+    pub fn is_allowed(&self, value: usize, position: usize) -> bool {
+        assert!(value < self.values && position < self.positions);
+        self.allowed[position * self.words + value / WORD] & (1 << (value % WORD)) != 0
+    }
+
+    /// Allow the value with the given index at a position.
+    pub fn allow(&mut self, value: usize, position: usize) {
+        assert!(value < self.values && position < self.positions);
+        self.allowed[position * self.words + value / WORD] |= 1 << (value % WORD);
+    }
+
+    /// Forbid the value with the given index at a position.
+    pub fn forbid(&mut self, value: usize, position: usize) {
+        assert!(value < self.values && position < self.positions);
+        self.allowed[position * self.words + value / WORD] &= !(1 << (value % WORD));
+    }
+
+    /// Whether any permutation satisfies the restriction, i.e. whether Hall's condition holds:
+    /// every set of positions allows at least as many values as it has positions.
+    ///
+    /// This is checked by looking for a matching of all positions to distinct values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use derangements::Restriction;
+    /// let mut restriction = Restriction::new(3, 2);
+    /// restriction.forbid(0, 0);
+    /// restriction.forbid(1, 0);
+    /// restriction.forbid(0, 1);
+    /// restriction.forbid(1, 1);
+    /// assert!(!restriction.is_satisfiable());
+    /// ```
+    pub fn is_satisfiable(&self) -> bool {
+        self.matching().is_some()
+    }
+
+    /// Find any assignment of distinct values to the positions, using augmenting paths.
+    pub(crate) fn matching(&self) -> Option<Vec<usize>> {
+        fn augment(
+            restriction: &Restriction,
+            position: usize,
+            visited: &mut [bool],
+            owner: &mut [Option<usize>],
+        ) -> bool {
+            for value in restriction.allowed_values(position) {
+                if !visited[value] {
+                    visited[value] = true;
+                    if owner[value].is_none_or(|other| augment(restriction, other, visited, owner))
+                    {
+                        owner[value] = Some(position);
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+
+        let mut owner = vec![None; self.values];
+        for position in 0..self.positions {
+            if !augment(self, position, &mut vec![false; self.values], &mut owner) {
+                return None;
+            }
+        }
+        let mut matching = vec![0; self.positions];
+        for (value, position) in owner.iter().enumerate() {
+            if let Some(position) = position {
+                matching[*position] = value;
+            }
+        }
+        Some(matching)
+    }
+
+    /// The allowed values of each position as a dense matrix.
+    #[cfg(feature = "rand")]
+    pub(crate) fn rows(&self) -> Vec<Vec<bool>> {
+        (0..self.positions)
+            .map(|position| {
+                (0..self.values)
+                    .map(|value| self.is_allowed(value, position))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn row(&self, position: usize) -> &[u64] {
+        &self.allowed[position * self.words..(position + 1) * self.words]
+    }
+
+    fn allowed_values(&self, position: usize) -> impl Iterator<Item = usize> + '_ {
+        self.row(position)
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &word)| {
+                (0..WORD)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| i * WORD + bit)
+            })
+    }
+}
+
+/// Enumerates the assignments of distinct values to all positions of a `Restriction`, which are
+/// the perfect matchings of the bipartite graph of allowed (value, position) pairs, optionally
+/// also subject to `PairConstraints`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "PerfectMatchingsState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct PerfectMatchings {
+    restriction: Restriction,
+    counts: Vec<usize>,
+    fewest_later: Vec<usize>,
+    pairs: PairConstraints,
+    search: Backtracking,
+    satisfiable: bool,
+}
+
+/// The fields of a deserialized `PerfectMatchings`, before they are checked for consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct PerfectMatchingsState {
+    restriction: Restriction,
+    counts: Vec<usize>,
+    fewest_later: Vec<usize>,
+    pairs: PairConstraints,
+    search: Backtracking,
+    satisfiable: bool,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<PerfectMatchingsState> for PerfectMatchings {
+    type Error = &'static str;
+
+    fn try_from(state: PerfectMatchingsState) -> Result<Self, Self::Error> {
+        let restriction = &state.restriction;
+        if state.search.dimensions() != (restriction.values, restriction.positions) {
+            return Err("search of a different size than the restriction");
+        }
+
+        // The other fields are derived from the restriction, so they have to match it
+        let matchings = perfect_matchings_from(state.restriction, state.search);
+        if matchings.counts != state.counts
+            || matchings.fewest_later != state.fewest_later
+            || matchings.satisfiable != state.satisfiable
+        {
+            return Err("allowed value counts don't match the restriction");
+        }
+        Ok(matchings.with_pairs(state.pairs))
+    }
+}
+
+/// Enumerate all assignments of distinct values to the positions of a restriction, in
+/// lexicographic order of the value indices.
+///
+/// This is a backtracking search that never places a forbidden value, and that abandons a partial
+/// assignment as soon as some later position has no value left. If the restriction is not
+/// satisfiable at all, this is found up front and nothing is searched.
+///
+/// # Arguments
+///
+/// * `restriction`: the allowed (value, position) pairs
+///
+/// returns: iterable with the value index for each position
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{perfect_matchings, Restriction};
+/// let mut restriction = Restriction::new(3, 2);
+/// restriction.forbid(0, 0);
+/// restriction.forbid(1, 1);
+/// assert_equal(perfect_matchings(restriction), [[1, 0], [1, 2], [2, 0]]);
+/// ```
+pub fn perfect_matchings(restriction: Restriction) -> PerfectMatchings {
+    let search = Backtracking::new(restriction.values, restriction.positions);
+    perfect_matchings_from(restriction, search)
+}
+
+fn perfect_matchings_from(restriction: Restriction, search: Backtracking) -> PerfectMatchings {
+    let counts = (0..restriction.positions)
+        .map(|position| restriction.allowed_values(position).count())
+        .collect::<Vec<_>>();
+
+    // Store the fewest allowed values of any later position, to know when to check them at all
+    let mut fewest_later = vec![usize::MAX; counts.len()];
+    for position in (1..counts.len()).rev() {
+        fewest_later[position - 1] = fewest_later[position].min(counts[position]);
+    }
+    let satisfiable = restriction.is_satisfiable();
+    PerfectMatchings {
+        restriction,
+        counts,
+        fewest_later,
+        pairs: PairConstraints::default(),
+        search,
+        satisfiable,
+    }
+}
+
+/// Whether value `index` can be placed after `prefix` (the indices of which are also given as the
+/// bitset `used`): it has to be allowed at that position, and every later position has to keep at
+/// least one allowed value that is not used yet.
+fn accepts(
+    restriction: &Restriction,
+    counts: &[usize],
+    fewest_later: &[usize],
+    prefix: &[usize],
+    used: &[u64],
+    index: usize,
+) -> bool {
+    let position = prefix.len();
+    if !restriction.is_allowed(index, position) {
+        return false;
+    }
+
+    // Only positions with at most as many allowed values as are placed can have run out
+    let placed = position + 1;
+    if fewest_later[position] > placed {
+        return true;
+    }
+    let (word, bit) = (index / WORD, 1 << (index % WORD));
+    (placed..restriction.positions)
+        .filter(|&later| counts[later] <= placed)
+        .all(|later| {
+            restriction
+                .row(later)
+                .iter()
+                .zip(used)
+                .enumerate()
+                .any(|(i, (allowed, used))| {
+                    allowed & !used & if i == word { !bit } else { u64::MAX } != 0
+                })
+        })
+}
+
+impl PerfectMatchings {
+    /// The matchings that start with `prefix`, which should itself be accepted.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn with_prefix(restriction: Restriction, prefix: &[usize]) -> Self {
+        let search = Backtracking::with_prefix(restriction.values, restriction.positions, prefix);
+        perfect_matchings_from(restriction, search)
+    }
+
+    /// Only the matchings that also satisfy the pair constraints.
+    pub(crate) fn with_pairs(self, pairs: PairConstraints) -> Self {
+        PerfectMatchings { pairs, ..self }
+    }
+
+    /// Move to the next matching, and return the value index for each position.
+    pub(crate) fn advance(&mut self) -> Option<&[usize]> {
+        let (restriction, counts, fewest_later) =
+            (&self.restriction, &self.counts, &self.fewest_later);
+        let (pairs, n, k) = (&self.pairs, restriction.values, restriction.positions);
+        if self.satisfiable
+            && self.search.advance(|prefix, used, index| {
+                accepts(restriction, counts, fewest_later, prefix, used, index)
+                    && pairs.accepts(prefix, used, index, n, k)
+            })
+        {
+            Some(self.search.indices())
+        } else {
+            None
+        }
+    }
+
+    /// Skip the next `skip` matchings, where `count` gives the number of matchings that start
+    /// with a given prefix.
+    pub(crate) fn skip_ahead<C>(&mut self, skip: u128, count: C)
+    where
+        C: FnMut(&[usize]) -> u128,
+    {
+        let (restriction, counts, fewest_later) =
+            (&self.restriction, &self.counts, &self.fewest_later);
+        let (pairs, n, k) = (&self.pairs, restriction.values, restriction.positions);
+        self.search.skip(
+            skip,
+            |prefix, used, index| {
+                accepts(restriction, counts, fewest_later, prefix, used, index)
+                    && pairs.accepts(prefix, used, index, n, k)
+            },
+            count,
+        );
+    }
+
+    /// The restriction that is being matched.
+    pub(crate) fn restriction(&self) -> &Restriction {
+        &self.restriction
+    }
+}
+
+impl Iterator for PerfectMatchings {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().map(|x| x.to_vec())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if !self.satisfiable {
+            return (0, Some(0));
+        }
+        let total =
+            counting::permutations::<u128>(self.restriction.values, self.restriction.positions);
+        (0, total.and_then(|x| usize::try_from(x).ok()))
+    }
+}
+
+impl FusedIterator for PerfectMatchings {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast_permutations;
+    use itertools::{assert_equal, Itertools};
+
+    fn brute_force(restriction: &Restriction) -> Vec<Vec<usize>> {
+        fast_permutations(0..restriction.values(), restriction.positions())
+            .filter(|x| {
+                x.iter()
+                    .enumerate()
+                    .all(|(position, &value)| restriction.is_allowed(value, position))
+            })
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_matchings_brute_force() {
+        // Forbid a pseudo-random selection of pairs, with increasing density
+        let mut seed = 12345u64;
+        for n in 0..=7 {
+            for k in 0..=n + 1 {
+                for density in [0, 2, 4, 6] {
+                    let mut restriction = Restriction::new(n, k);
+                    for value in 0..n {
+                        for position in 0..k {
+                            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                            if (seed >> 60) < density {
+                                restriction.forbid(value, position);
+                            }
+                        }
+                    }
+                    let expected = brute_force(&restriction);
+                    assert_eq!(restriction.is_satisfiable(), !expected.is_empty());
+                    assert_equal(perfect_matchings(restriction), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_restriction() {
+        // More than one word of values per position
+        let mut restriction = Restriction::new(70, 2);
+        for value in 0..69 {
+            restriction.forbid(value, 0);
+        }
+        restriction.forbid(69, 1);
+        assert!(restriction.is_allowed(69, 0));
+        assert!(!restriction.is_allowed(68, 0));
+        assert_eq!(perfect_matchings(restriction.clone()).count(), 69);
+        restriction.forbid(69, 0);
+        assert!(!restriction.is_satisfiable());
+        assert_eq!(perfect_matchings(restriction).next(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_is_allowed_out_of_range() {
+        // A value past the end of the first position's word would read the next position's
+        Restriction::new(3, 2).is_allowed(64, 0);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let values = vec![2usize, 0, 1, 3];
+        let restriction = Restriction::from_indices(&values, 3);
+        assert!(!restriction.is_allowed(0, 2) && !restriction.is_allowed(1, 0));
+        assert!(restriction.is_allowed(3, 0) && restriction.is_allowed(0, 0));
+
+        let restriction = Restriction::from_sequence(&values, 4, &[0, 0, 3]);
+        assert!(!restriction.is_allowed(1, 0) && !restriction.is_allowed(1, 1));
+        assert!(!restriction.is_allowed(3, 2) && restriction.is_allowed(3, 3));
+
+        let restrict = HashMap::from([(1, vec![2, 3]), (7, vec![0])]);
+        let restriction = Restriction::from_map_index(&values, 4, &restrict);
+        assert!(!restriction.is_allowed(0, 1) && !restriction.is_allowed(3, 1));
+        assert!(restriction.is_allowed(1, 1) && restriction.is_allowed(0, 0));
+
+        let restrict = HashMap::from([(2, vec![1, 3, 9])]);
+        let restriction = Restriction::from_map_value(&values, 4, &restrict);
+        assert!(!restriction.is_allowed(0, 1) && !restriction.is_allowed(0, 3));
+        assert!(restriction.is_allowed(0, 0) && restriction.is_allowed(1, 1));
+
+        let restriction = Restriction::from_fn(&values, 4, |position, &x| x <= position);
+        assert!(!restriction.is_allowed(0, 1) && !restriction.is_allowed(3, 2));
+        assert!(restriction.is_allowed(0, 2) && restriction.is_allowed(1, 0));
+    }
+}
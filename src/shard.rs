@@ -0,0 +1,76 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// One of `count` disjoint parts of an enumeration, to split it over separate jobs.
+///
+/// Each shard covers a contiguous run of the output, and the sizes of the shards differ by at
+/// most one, so running shards `0..count` one after the other gives the full output.
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{derangements_by_range, Shard};
+/// let shards = (0..3).flat_map(|index| derangements_by_range(5).shard(Shard::new(index, 3)));
+/// assert_equal(shards, derangements_by_range(5));
+///
+/// let shard = Shard::new(1, 3);
+/// assert_eq!((shard.index(), shard.count()), (1, 3));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ShardState"))]
+pub struct Shard {
+    index: usize,
+    count: usize,
+}
+
+/// The fields of a deserialized `Shard`, before the index is checked
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ShardState {
+    index: usize,
+    count: usize,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ShardState> for Shard {
+    type Error = &'static str;
+
+    fn try_from(state: ShardState) -> Result<Self, Self::Error> {
+        if state.index >= state.count {
+            return Err("shard index out of range");
+        }
+        Ok(Shard::new(state.index, state.count))
+    }
+}
+
+impl Shard {
+    /// Create the shard with a given index out of `count` shards.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is not less than `count`.
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(index < count, "shard index out of range");
+        Shard { index, count }
+    }
+
+    /// The index of this shard, which is less than `count`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The number of shards that the enumeration is split into.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The start and end (exclusive) of this shard within a run of `total` items
+    pub(crate) fn bounds(&self, total: u128) -> (u128, u128) {
+        assert!(self.index < self.count, "shard index out of range");
+        let count = self.count as u128;
+        let split = |i: u128| total / count * i + total % count * i / count;
+        (split(self.index as u128), split(self.index as u128 + 1))
+    }
+}
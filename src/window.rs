@@ -0,0 +1,290 @@
+use crate::counting;
+use crate::derangements_range::{derangements_by_range, DerangementsRangeIterator};
+use crate::restriction::{perfect_matchings, PerfectMatchings, Restriction};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::iter::FusedIterator;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum WindowSearch {
+    Range(DerangementsRangeIterator),
+    Matchings(PerfectMatchings),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "WindowDerangementsState"))]
+#[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
+pub struct WindowDerangementsIterator {
+    search: WindowSearch,
+    n: usize,
+    w: usize,
+    circular: bool,
+    generated: u128,
+    /// The total number of permutations, which is only counted once it is asked for
+    #[cfg_attr(feature = "serde", serde(skip))]
+    total: OnceLock<Option<u128>>,
+}
+
+/// The fields of a deserialized `WindowDerangementsIterator`, before they are checked for
+/// consistency
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct WindowDerangementsState {
+    search: WindowSearch,
+    n: usize,
+    w: usize,
+    circular: bool,
+    generated: u128,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<WindowDerangementsState> for WindowDerangementsIterator {
+    type Error = &'static str;
+
+    fn try_from(state: WindowDerangementsState) -> Result<Self, Self::Error> {
+        let (n, w, circular) = (state.n, state.w, state.circular);
+        let matches = match &state.search {
+            WindowSearch::Range(derangements) => w == 1 && derangements.n() == n,
+            WindowSearch::Matchings(matchings) => {
+                w != 1 && *matchings.restriction() == window_restriction(n, w, circular)
+            }
+        };
+        if !matches {
+            return Err("search doesn't match the window");
+        }
+
+        // The derangements of a window of one know exactly how many are left
+        let total = counting::window_derangements::<u128>(n, w, circular);
+        let generated = match (&state.search, total) {
+            (WindowSearch::Range(derangements), Some(total)) => derangements
+                .remaining()
+                .and_then(|remaining| total.checked_sub(remaining)),
+            _ => None,
+        };
+        if total.is_some_and(|total| state.generated > total)
+            || generated.is_some_and(|generated| generated != state.generated)
+        {
+            return Err("generated permutations don't match the search");
+        }
+        Ok(WindowDerangementsIterator {
+            search: state.search,
+            n,
+            w,
+            circular,
+            generated: state.generated,
+            total: OnceLock::from(total),
+        })
+    }
+}
+
+/// The restriction where value v can't be at positions v up to v + w - 1, which wrap around to
+/// the start in a circular window.
+fn window_restriction(n: usize, w: usize, circular: bool) -> Restriction {
+    let mut restriction = Restriction::new(n, n);
+    for value in 0..n {
+        for position in (value..value + w.min(n)).filter(|&x| circular || x < n) {
+            restriction.forbid(value, position % n);
+        }
+    }
+    restriction
+}
+
+/// Permute the range of 0 to n (non-inclusive) such that no element i is in the window of
+/// positions i up to i + w - 1.
+///
+/// With `circular`, the window wraps around, so that with w = 2 element n - 1 can't be at position
+/// 0 either: this is the ménage problem. Otherwise the window ends at the last position.
+/// For w = 1 these are the derangements of `derangements_by_range` (in the same order), and
+/// otherwise each permutation is built directly by backtracking, in lexicographic order.
+///
+/// # Arguments
+///
+/// * `n`: usize integer that determines the range to permute
+/// * `w`: usize integer that determines the width of the window of forbidden positions
+/// * `circular`: whether the window wraps around from the last position to the first one
+///
+/// returns: iterable with the permutations
+///
+/// # Examples
+///
+/// ```
+/// use itertools::assert_equal;
+/// use derangements::{derangements_by_range, derangements_by_range_window};
+///
+/// // No element at its own index or at the next one, and the last element not at the start
+/// assert_equal(derangements_by_range_window(4, 2, true), [[1, 2, 3, 0], [2, 3, 0, 1]]);
+/// assert_equal(derangements_by_range_window(4, 2, false),
+///     [[1, 2, 3, 0], [2, 3, 0, 1], [3, 2, 0, 1]]);
+///
+/// // A window of one is a derangement
+/// assert_equal(derangements_by_range_window(5, 1, true), derangements_by_range(5));
+/// ```
+pub fn derangements_by_range_window(
+    n: usize,
+    w: usize,
+    circular: bool,
+) -> WindowDerangementsIterator {
+    let search = if w == 1 {
+        WindowSearch::Range(derangements_by_range(n))
+    } else {
+        WindowSearch::Matchings(perfect_matchings(window_restriction(n, w, circular)))
+    };
+    WindowDerangementsIterator {
+        search,
+        n,
+        w,
+        circular,
+        generated: 0,
+        total: OnceLock::new(),
+    }
+}
+
+impl Iterator for WindowDerangementsIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match &mut self.search {
+            WindowSearch::Range(derangements) => derangements.next(),
+            WindowSearch::Matchings(matchings) => matchings.next(),
+        };
+        if next.is_some() {
+            self.generated += 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        counting::size_hint(self.remaining())
+    }
+}
+
+impl WindowDerangementsIterator {
+    /// The exact number of permutations that are left, or `None` if it does not fit in a `u128`
+    /// or the window is too wide to count (see `count_derangements_by_range_window`).
+    ///
+    /// The total is only counted on the first call, so creating the iterator is always cheap.
+    pub fn remaining(&self) -> Option<u128> {
+        let total = self
+            .total
+            .get_or_init(|| counting::window_derangements(self.n, self.w, self.circular));
+        total.and_then(|total| total.checked_sub(self.generated))
+    }
+}
+
+impl FusedIterator for WindowDerangementsIterator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast_permutations;
+    use itertools::{assert_equal, Itertools};
+
+    fn in_window(n: usize, w: usize, circular: bool, position: usize, value: usize) -> bool {
+        if circular {
+            (position + n - value) % n < w
+        } else {
+            position >= value && position - value < w
+        }
+    }
+
+    #[test]
+    fn test_window_brute_force() {
+        for n in 0..=7 {
+            for w in 0..=n + 1 {
+                for circular in [false, true] {
+                    let expected = fast_permutations(0..n, n)
+                        .filter(|x| {
+                            x.iter().enumerate().all(|(position, &value)| {
+                                !in_window(n, w, circular, position, value)
+                            })
+                        })
+                        .sorted()
+                        .collect_vec();
+                    let iter = derangements_by_range_window(n, w, circular);
+                    assert_eq!(iter.remaining(), Some(expected.len() as u128));
+                    assert_equal(iter.sorted(), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_menage() {
+        // The ménage numbers, for the circular window of two
+        let expected = [1, 0, 0, 1, 2, 13, 80, 579, 4738, 43387];
+        for (n, &count) in expected.iter().enumerate() {
+            assert_eq!(derangements_by_range_window(n, 2, true).count(), count);
+        }
+    }
+
+    #[test]
+    fn test_wide_window() {
+        // Too wide to count, which doesn't keep the permutations from being generated
+        for (n, w) in [(30, 15), (40, 16)] {
+            let mut iter = derangements_by_range_window(n, w, false);
+            let first = iter.next().unwrap();
+            assert!(first
+                .iter()
+                .enumerate()
+                .all(|(position, &value)| { !in_window(n, w, false, position, value) }));
+            assert_eq!(iter.remaining(), None);
+            assert_eq!(iter.size_hint(), (usize::MAX, None));
+        }
+        let mut iter = derangements_by_range_window(8, 3, true);
+        assert_eq!(iter.remaining(), counting::window_derangements(8, 3, true));
+        iter.next();
+        assert_eq!(
+            iter.remaining(),
+            counting::window_derangements::<u128>(8, 3, true).map(|x| x - 1)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut iter = derangements_by_range_window(7, 2, true);
+        iter.nth(100);
+        let json = serde_json::to_string(&iter).unwrap();
+        let resumed: WindowDerangementsIterator = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.remaining(), iter.remaining());
+        assert_equal(resumed, iter);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_invalid() {
+        let resume = |x| serde_json::from_value::<WindowDerangementsIterator>(x).is_err();
+        let mut iter = derangements_by_range_window(7, 2, true);
+        iter.nth(100);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(!resume(state.clone()));
+
+        let mut corrupted = state.clone();
+        corrupted["n"] = 8.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["w"] = 3.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state.clone();
+        corrupted["circular"] = false.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["generated"] = 1000.into();
+        assert!(resume(corrupted));
+
+        // A window of one is checked against the derangements that are left
+        let mut iter = derangements_by_range_window(6, 1, false);
+        iter.nth(100);
+        let state = serde_json::to_value(&iter).unwrap();
+        assert!(!resume(state.clone()));
+        let mut corrupted = state.clone();
+        corrupted["generated"] = 50.into();
+        assert!(resume(corrupted));
+        let mut corrupted = state;
+        corrupted["w"] = 2.into();
+        assert!(resume(corrupted));
+    }
+}